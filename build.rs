@@ -1,6 +1,11 @@
 use tonic_build;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/weve_market.proto")?;
+    tonic_build::configure()
+        // Requests are deduplicated across an appraisal, so they key maps
+        .type_attribute("weve_esi_proto.MarketOrdersReq", "#[derive(Eq, Hash)]")
+        .type_attribute("weve_esi_proto.AdjustedPriceReq", "#[derive(Eq, Hash)]")
+        .type_attribute("weve_esi_proto.SystemIndexReq", "#[derive(Eq, Hash)]")
+        .compile(&["proto/weve_market.proto"], &["proto"])?;
     Ok(())
-}
\ No newline at end of file
+}
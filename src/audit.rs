@@ -0,0 +1,153 @@
+use crate::{
+    {ItemName, Quantity, TypeId, Priced, is_error},
+    pricing::{Price, Selected},
+    config::Config,
+    rpc::{Req, Reps, plan},
+    history::now,
+    error::Error,
+    proto::*,
+};
+
+use std::env::var;
+
+use firestore::FirestoreDb;
+use serde::{Serialize, Deserialize};
+
+const AUDIT_COLLECTION: &str = "hash_audit";
+
+// The orders behind each accepted item of a quote, stored under the quote's
+// hash so that a disputed quote can be reconstructed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Audit {
+    pub hash: String,
+    pub timestamp: u64,
+    pub items: Vec<AuditItem>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditItem {
+    pub name: ItemName,
+    pub quantity: Quantity,
+    pub books: Vec<AuditBook>,
+}
+
+// One side of one market's book, as it was when its data was fetched
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditBook {
+    pub type_id: TypeId,
+    pub market: String,
+    pub buy: bool,
+    pub fetched: Option<u64>,
+    pub orders: Vec<AuditOrder>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditOrder {
+    pub price: f64,
+    pub quantity: i32,
+    pub selected: bool, // the price was taken from this order
+}
+
+// Quotes are only audited when BBBE_AUDIT is set
+pub fn enabled() -> bool {
+    var("BBBE_AUDIT").is_ok()
+}
+
+impl Audit {
+    // Records the books consulted for every accepted item of priced
+    pub fn new(config: &Config, priced: &[Priced], reps: &Reps) -> Self {
+        Audit {
+            hash: String::new(),
+            timestamp: now(),
+            items: priced
+                .iter()
+                .filter_map(|(item, pricing_model, priced)| match priced {
+                    Ok((Price::Accepted(_), _)) => Some(AuditItem {
+                        name: item.name.clone(),
+                        quantity: item.quantity,
+                        books: audit_books(
+                            pricing_model.to_reqs(config),
+                            pricing_model.selected(config, reps, item.quantity),
+                            reps,
+                        ),
+                    }),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    pub async fn store(
+        mut self,
+        db: &FirestoreDb,
+        hash: &str,
+    ) -> Result<(), Error> {
+        self.hash = hash.to_string();
+        match db
+            .fluent()
+            .insert()
+            .into(AUDIT_COLLECTION)
+            .document_id(hash)
+            .object(&self)
+            .execute::<Audit>()
+            .await
+        {
+            Err(e) if is_error(&e) => Err(Error::FirestoreInsertError(e)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, Error> {
+        serde_json::to_string(self)
+            .map_err(|e| Error::SerializationError(e))
+    }
+}
+
+pub async fn audit_from_hash(
+    hash: &str,
+    db: &FirestoreDb,
+) -> Result<Option<Audit>, Error> {
+    db
+        .fluent()
+        .select()
+        .by_id_in(AUDIT_COLLECTION)
+        .obj()
+        .one(hash)
+        .await
+        .map_err(|e| Error::FirestoreSelectError(e))
+}
+
+fn audit_books(
+    reqs: Vec<Req>,
+    selected: Vec<Selected>,
+    reps: &Reps,
+) -> Vec<AuditBook> {
+    plan(reqs)
+        .into_iter()
+        .filter_map(|req| {
+            let fetched: Option<u64> = reps.fetched(&req);
+            let req: MarketOrdersReq = match req {
+                Req::MarketOrders(r) => r,
+                _ => return None,
+            };
+            let rep: &MarketOrdersRep = reps.market_orders(&req)?;
+            Some(AuditBook {
+                orders: rep
+                    .market_orders
+                    .iter()
+                    .map(|o| AuditOrder {
+                        price: o.price,
+                        quantity: o.quantity,
+                        selected: selected
+                            .iter()
+                            .any(|(sreq, so)| sreq == &req && so == o),
+                    })
+                    .collect(),
+                type_id: req.type_id,
+                market: req.market,
+                buy: req.buy,
+                fetched: fetched,
+            })
+        })
+        .collect()
+}
@@ -1,33 +1,60 @@
 use wetc_buyback_backend::{
     response_from_items,
     response_from_hash,
+    audit_from_hash,
+    record_snapshots,
+    read_config_path,
     ParsedInput,
     Response,
     Client,
+    Cache,
+    Config,
     Error,
 };
 
 use std::{
     net::SocketAddr,
     env::var,
+    fs,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
 };
 
 use hyper::{self, service::service_fn, server::conn::Http, Body};
 use futures::stream::TryStreamExt;
-use tokio::net::TcpListener;
+use tokio::{net::TcpListener, signal::unix::{signal, SignalKind}};
 use firestore::FirestoreDb;
 use serde_json::json;
 
 static mut DB: Option<FirestoreDb> = None;
 static mut CLIENT: Option<Client> = None;
+static mut CACHE: Option<Cache> = None;
+static mut ADMIN_TOKEN: Option<String> = None;
+// Appraisals hold the Arc they started with, so a reload swaps the config
+// without changing it under them
+static CONFIG: RwLock<Option<Arc<Config>>> = RwLock::new(None);
+static CONFIG_MODIFIED: Mutex<Option<SystemTime>> = Mutex::new(None);
 
 #[tokio::main]
 async fn main() {
     let listener: TcpListener = get_listener().await.unwrap();
+    *CONFIG_MODIFIED.lock().unwrap() = get_config_modified();
+    *CONFIG.write().unwrap() = Some(Arc::new(
+        wetc_buyback_backend::get_config().unwrap(),
+    ));
     unsafe {
         CLIENT = Some(wetc_buyback_backend::get_client().await.unwrap());
         DB = Some(wetc_buyback_backend::get_db().await.unwrap());
+        CACHE = get_cache();
+        ADMIN_TOKEN = get_admin_token();
     }
+    if let Some(interval) = get_snapshot_interval() {
+        tokio::task::spawn(record_snapshots_every(interval));
+    }
+    if let Some(interval) = get_config_poll_interval() {
+        tokio::task::spawn(reload_config_when_modified(interval));
+    }
+    tokio::task::spawn(reload_config_on_hangup());
 
     loop {
         let (stream, _) = listener
@@ -66,6 +93,27 @@ async fn serve(
             None => (),
         },
     };
+    if let "/cache" | "/reload" = req.uri().path() {
+        if let Some(denied) = authorize_admin(&req) {
+            return Ok(denied);
+        }
+    }
+    if req.uri().path() == "/cache" {
+        return Ok(match get_cache_ref() {
+            Some(cache) => success_response(
+                json!(cache.stats()).to_string(),
+            ),
+            None => success_response(json!(null).to_string()),
+        });
+    }
+    if req.uri().path() == "/reload" {
+        return Ok(match reload_config("request") {
+            Ok(version) => success_response(
+                json!({"version": version}).to_string(),
+            ),
+            Err(e) => err_response(e),
+        });
+    }
 
     let buf: Vec<u8> = unwrap_or_rep!(req
         .into_body()
@@ -77,10 +125,13 @@ async fn serve(
         .map_err(|e| Error::HyperRequestBodyError(e.into()))
     );
 
-    let parsed_input = unwrap_or_rep!(ParsedInput::from_slice(&buf));
+    let config: Arc<Config> = get_config();
+    let parsed_input = unwrap_or_rep!(ParsedInput::from_slice(
+        &buf, &config,
+    ));
     let response: Response = match parsed_input {
         ParsedInput::Items((v, l)) => unwrap_or_rep!(response_from_items(
-            v, l, get_db(), get_client(),
+            v, l, get_db(), get_client(), get_cache_ref(), &config,
         )
             .await
         ),
@@ -89,6 +140,15 @@ async fn serve(
         )
             .await
         ),
+        ParsedInput::Audit(h) => return Ok(match unwrap_or_rep!(
+            audit_from_hash(h, get_db()).await
+        ) {
+            Some(audit) => match audit.to_json() {
+                Ok(j) => success_response(j),
+                Err(e) => err_response(e),
+            },
+            None => success_response(json!(null).to_string()),
+        }),
     };
 
     match response.to_json() {
@@ -102,6 +162,26 @@ fn validate(_req: &hyper::Request<Body>) -> Option<hyper::Response<Body>> {
     None
 }
 
+// Return a response only if the request may not use the admin endpoints
+fn authorize_admin(req: &hyper::Request<Body>) -> Option<hyper::Response<Body>> {
+    let token: &str = match get_admin_token_ref() {
+        Some(token) => token,
+        None => return Some(status_response(hyper::StatusCode::NOT_FOUND)),
+    };
+    match req.headers().get("X-Admin-Token") {
+        Some(given) if constant_time_eq(given.as_bytes(), token.as_bytes()) => None,
+        _ => Some(status_response(hyper::StatusCode::FORBIDDEN)),
+    }
+}
+
+// Compares every byte so the time taken does not reveal the matching prefix
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a
+        .iter()
+        .zip(b.iter())
+        .fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
 fn get_client() -> &'static Client {
     unsafe {
         match &CLIENT {
@@ -111,6 +191,25 @@ fn get_client() -> &'static Client {
     }
 }
 
+fn get_config() -> Arc<Config> {
+    match CONFIG.read().unwrap().as_ref() {
+        Some(c) => c.clone(),
+        None => unreachable!(),
+    }
+}
+
+fn get_cache_ref() -> Option<&'static Cache> {
+    unsafe {
+        CACHE.as_ref()
+    }
+}
+
+fn get_admin_token_ref() -> Option<&'static str> {
+    unsafe {
+        ADMIN_TOKEN.as_deref()
+    }
+}
+
 fn get_db() -> &'static FirestoreDb {
     unsafe {
         match &DB {
@@ -150,6 +249,117 @@ fn err_response(error: Error) -> hyper::Response<Body> {
         .unwrap()
 }
 
+fn status_response(status: hyper::StatusCode) -> hyper::Response<Body> {
+    hyper::Response::builder()
+        .status(status)
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Content-Type", "application/json")
+        .body(Body::from(
+            json!({"error": status.to_string()}).to_string(),
+        ))
+        .unwrap()
+}
+
+// The admin endpoints /cache and /reload are only served when
+// BBBE_ADMINTOKEN is set, to requests sending it as X-Admin-Token
+fn get_admin_token() -> Option<String> {
+    var("BBBE_ADMINTOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+}
+
+// Market orders are only cached when BBBE_CACHETTL (seconds) is set
+fn get_cache() -> Option<Cache> {
+    let ttl: u64 = var("BBBE_CACHETTL")
+        .ok()?
+        .parse()
+        .unwrap();
+    let capacity: usize = var("BBBE_CACHESIZE")
+        .map(|s| s.parse().unwrap())
+        .unwrap_or(10000);
+    Some(Cache::new(Duration::from_secs(ttl), capacity))
+}
+
+// Snapshots are only recorded when BBBE_SNAPSHOTINTERVAL (seconds) is set
+fn get_snapshot_interval() -> Option<Duration> {
+    var("BBBE_SNAPSHOTINTERVAL")
+        .ok()
+        .map(|s| s.parse::<u64>().unwrap())
+        .map(Duration::from_secs)
+}
+
+async fn record_snapshots_every(interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        match record_snapshots(
+            get_db(),
+            get_client().clone(),
+            &get_config(),
+        ).await {
+            Ok(recorded) => println!("Recorded {} price snapshots", recorded),
+            Err(err) => println!("Error recording price snapshots: {:?}", err),
+        }
+    }
+}
+
+// Swaps in the config file if it parses and validates, otherwise keeps
+// serving the current config. Returns the version now serving.
+fn reload_config(trigger: &str) -> Result<String, Error> {
+    let modified: Option<SystemTime> = get_config_modified();
+    let loaded: Result<Config, Error> = wetc_buyback_backend::get_config();
+    *CONFIG_MODIFIED.lock().unwrap() = modified; // Retry once modified again
+    match loaded {
+        Ok(config) => {
+            let version: String = config.version.clone();
+            *CONFIG.write().unwrap() = Some(Arc::new(config));
+            println!("Reloaded pricing config version {} on {}", version, trigger);
+            Ok(version)
+        },
+        Err(err) => {
+            println!(
+                "Rejected pricing config on {}, still serving version {}: {:?}",
+                trigger,
+                get_config().version,
+                err,
+            );
+            Err(err)
+        },
+    }
+}
+
+fn get_config_modified() -> Option<SystemTime> {
+    fs::metadata(read_config_path().ok()?)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+// The config file is only polled when BBBE_CONFIGPOLL (seconds) is set
+fn get_config_poll_interval() -> Option<Duration> {
+    var("BBBE_CONFIGPOLL")
+        .ok()
+        .map(|s| s.parse::<u64>().unwrap())
+        .map(Duration::from_secs)
+}
+
+async fn reload_config_when_modified(interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let modified: Option<SystemTime> = get_config_modified();
+        if modified != *CONFIG_MODIFIED.lock().unwrap() {
+            let _ = reload_config("modification");
+        }
+    }
+}
+
+async fn reload_config_on_hangup() {
+    let mut hangup = signal(SignalKind::hangup()).unwrap();
+    while hangup.recv().await.is_some() {
+        let _ = reload_config("SIGHUP");
+    }
+}
+
 async fn get_listener() -> Result<TcpListener, Error> {
     let socket: SocketAddr = var("BBBE_LISTENADDR")?
         .to_string()
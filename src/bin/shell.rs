@@ -1,33 +1,101 @@
 use wetc_buyback_backend::{
     shell_response_from_items,
     shell_response_from_hash,
+    shell_audit_from_hash,
+    shell_record_snapshots,
+    get_config,
+    read_config_path,
     ParsedInput,
+    Config,
     Response,
     Error,
 };
 
-use std::io::{self, Read};
+use std::{
+    io::{self, Read},
+    env::args,
+    process::exit,
+};
 
 use tokio;
 
 #[tokio::main]
 async fn main() {
+    match args().nth(1).as_deref() {
+        Some("validate-config") => validate_config(args().nth(2)),
+        Some("diff-config") => diff_config(
+            args().nth(2).expect("diff-config <old> <new>"),
+            args().nth(3).expect("diff-config <old> <new>"),
+        ),
+        _ => (),
+    }
+    let config: Config = get_config().unwrap();
+
+    match args().nth(1).as_deref() {
+        Some("snapshot") => {
+            let recorded: usize = shell_record_snapshots(&config).await.unwrap();
+            println!("Recorded {} price snapshots", recorded);
+            return;
+        },
+        _ => (),
+    }
+
     let mut buf: String = String::new();
     read_stdin(&mut buf).unwrap();
 
-    let parsed_input: ParsedInput = ParsedInput::from_str(&buf).unwrap();
+    let parsed_input: ParsedInput = ParsedInput::from_str(&buf, &config).unwrap();
     let response: Response = match parsed_input {
-        ParsedInput::Items((v, l)) => shell_response_from_items(v, l)
+        ParsedInput::Items((v, l)) => shell_response_from_items(v, l, &config)
             .await
             .unwrap(),
         ParsedInput::Hash(s) => shell_response_from_hash(s)
             .await
             .unwrap(),
+        ParsedInput::Audit(s) => {
+            match shell_audit_from_hash(s).await.unwrap() {
+                Some(audit) => println!("{}", audit.to_json().unwrap()),
+                None => println!("null"),
+            }
+            return;
+        },
     };
 
     response.to_stdout().unwrap();
 }
 
+// Lints the config at path, or at BBBE_CONFIG, printing every problem and
+// exiting nonzero if there are any
+fn validate_config(path: Option<String>) -> ! {
+    let path: String = path.unwrap_or_else(|| read_config_path().unwrap());
+    let config: Config = match Config::read(&path) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("{}: {}", path, err);
+            exit(1);
+        },
+    };
+    let problems: Vec<String> = config.lint();
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    println!("{} problems in {} version {}", problems.len(), path, config.version);
+    exit(match problems.is_empty() {
+        true => 0,
+        false => 1,
+    });
+}
+
+// Lists the items whose pricing changed between the configs at old and new
+fn diff_config(old: String, new: String) -> ! {
+    let old: Config = Config::read(&old).unwrap();
+    let new: Config = Config::read(&new).unwrap();
+    println!("{} -> {}", old.version, new.version);
+    for change in old.diff(&new) {
+        println!("{}", change);
+    }
+    exit(0);
+}
+
 fn read_stdin(buf: &mut String) -> Result<(), Error> {
     io::stdin()
         .read_to_string(buf)
@@ -0,0 +1,288 @@
+use crate::{
+    history::now,
+    error::Error,
+    proto::*,
+};
+
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::{Arc, Weak, Mutex, atomic::{AtomicU64, Ordering}},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::OnceCell;
+
+// Market order books shared between appraisals, so that concurrent and
+// repeated appraisals fetch each book at most once per ttl
+pub struct Cache {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+// The entries with the order they were inserted in, oldest first, so that
+// eviction does not scan the map
+#[derive(Default)]
+struct Entries {
+    map: HashMap<MarketOrdersReq, Arc<Entry>>,
+    order: VecDeque<(MarketOrdersReq, Weak<Entry>)>, // may hold dropped entries
+}
+
+// Empty until its fetch succeeds, its ttl runs from when the fetch completed
+struct Entry {
+    rep: OnceCell<(MarketOrdersRep, u64, Instant)>, // with when it was fetched
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub size: usize,
+}
+
+impl Cache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Cache {
+            ttl: ttl,
+            capacity: capacity.max(1), // room for the entry being fetched
+            entries: Mutex::new(Entries::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size: self.entries.lock().unwrap().map.len(),
+        }
+    }
+
+    // Returns the cached book for req and when it was fetched, or fetches it.
+    // Concurrent misses on the same req wait for the first fetch instead of
+    // sending their own, and only send theirs if it fails.
+    pub async fn get_or_fetch<F, Fut>(
+        &self,
+        req: &MarketOrdersReq,
+        fetch: F,
+    ) -> Result<(MarketOrdersRep, u64), Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<MarketOrdersRep, Error>>,
+    {
+        let entry: Arc<Entry> = self.entry(req);
+        let mut fetched: bool = false;
+        let result = entry
+            .rep
+            .get_or_try_init(|| {
+                fetched = true;
+                async move {
+                    Ok::<_, Error>((fetch().await?, now(), Instant::now()))
+                }
+            })
+            .await;
+        let (rep, timestamp, _) = match result {
+            Ok(rep) => rep,
+            Err(e) => {
+                self.remove(req, &entry);
+                return Err(e);
+            },
+        };
+        match fetched {
+            true => self.misses.fetch_add(1, Ordering::Relaxed),
+            false => self.hits.fetch_add(1, Ordering::Relaxed),
+        };
+        Ok((rep.clone(), *timestamp))
+    }
+
+    // Returns the live or still fetching entry for req, replacing it if it
+    // has expired
+    fn entry(&self, req: &MarketOrdersReq) -> Arc<Entry> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.map.get(req) {
+            match entry.rep.get() {
+                Some((_, _, fetched)) if fetched.elapsed() >= self.ttl => {
+                    entries.map.remove(req);
+                },
+                _ => return entry.clone(),
+            }
+        }
+        entries.evict(self.capacity);
+        let entry: Arc<Entry> = Arc::new(Entry {
+            rep: OnceCell::new(),
+        });
+        entries.insert(req.clone(), entry.clone());
+        entry
+    }
+
+    // Drops entry after its fetch failed, unless another fetch has filled it
+    // or it has been replaced since
+    fn remove(&self, req: &MarketOrdersReq, entry: &Arc<Entry>) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries
+            .map
+            .get(req)
+            .is_some_and(|e| Arc::ptr_eq(e, entry) && e.rep.get().is_none())
+        {
+            entries.map.remove(req);
+        }
+    }
+}
+
+impl Entries {
+    fn insert(&mut self, req: MarketOrdersReq, entry: Arc<Entry>) {
+        self.order.push_back((req.clone(), Arc::downgrade(&entry)));
+        self.map.insert(req, entry);
+        if self.order.len() > self.map.len() * 2 { // Too many dropped entries
+            let map = &self.map;
+            self.order.retain(|(req, entry)| is_current(map, req, entry));
+        }
+    }
+
+    // Drops the oldest entries until there is room for one more, which are
+    // the ones that expire first
+    fn evict(&mut self, capacity: usize) {
+        while self.map.len() >= capacity {
+            match self.order.pop_front() {
+                Some((req, entry)) => if is_current(&self.map, &req, &entry) {
+                    self.map.remove(&req);
+                },
+                None => break,
+            }
+        }
+    }
+}
+
+// Whether entry is still the one cached for req
+fn is_current(
+    map: &HashMap<MarketOrdersReq, Arc<Entry>>,
+    req: &MarketOrdersReq,
+    entry: &Weak<Entry>,
+) -> bool {
+    map
+        .get(req)
+        .is_some_and(|e| Arc::as_ptr(e) == entry.as_ptr())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn req(type_id: i32) -> MarketOrdersReq {
+        MarketOrdersReq {
+            type_id: type_id,
+            market: "Jita".to_string(),
+            buy: true,
+        }
+    }
+
+    // Gets req's book through cache, counting the fetches sent
+    async fn get(cache: &Cache, type_id: i32, fetches: &AtomicUsize) -> MarketOrdersRep {
+        cache
+            .get_or_fetch(&req(type_id), || async {
+                fetches.fetch_add(1, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+                Ok(MarketOrdersRep {
+                    market_orders: vec![MarketOrder {
+                        quantity: 1,
+                        price: type_id as f64,
+                    }],
+                })
+            })
+            .await
+            .unwrap()
+            .0
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_fetch_once() {
+        let cache: Cache = Cache::new(Duration::from_secs(60), 10);
+        let fetches: AtomicUsize = AtomicUsize::new(0);
+        let (a, b) = tokio::join!(get(&cache, 34, &fetches), get(&cache, 34, &fetches));
+        assert_eq!(a, b);
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        let stats: CacheStats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.size), (1, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn failed_fetches_are_retried_and_not_cached() {
+        let cache: Cache = Cache::new(Duration::from_secs(60), 10);
+        let failed = cache
+            .get_or_fetch(&req(34), || async { Err(Error::GRPCTimeout) })
+            .await;
+        assert!(failed.is_err());
+        assert_eq!(cache.stats().size, 0);
+
+        let fetches: AtomicUsize = AtomicUsize::new(0);
+        get(&cache, 34, &fetches).await;
+        get(&cache, 34, &fetches).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.stats().size, 1);
+    }
+
+    #[tokio::test]
+    async fn entries_expire_after_ttl() {
+        let cache: Cache = Cache::new(Duration::from_millis(20), 10);
+        let fetches: AtomicUsize = AtomicUsize::new(0);
+        get(&cache, 34, &fetches).await;
+        get(&cache, 34, &fetches).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        std::thread::sleep(Duration::from_millis(30));
+        get(&cache, 34, &fetches).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.stats().size, 1);
+    }
+
+    #[tokio::test]
+    async fn expired_entries_do_not_pile_up_in_the_order() {
+        let cache: Cache = Cache::new(Duration::ZERO, 10);
+        let fetches: AtomicUsize = AtomicUsize::new(0);
+        for _ in 0..100 {
+            get(&cache, 34, &fetches).await;
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 100);
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.map.len(), 1);
+        assert!(entries.order.len() <= 2);
+    }
+
+    #[tokio::test]
+    async fn evicts_the_oldest_entry_at_capacity() {
+        let cache: Cache = Cache::new(Duration::from_secs(60), 2);
+        let fetches: AtomicUsize = AtomicUsize::new(0);
+        let failed = cache
+            .get_or_fetch(&req(1), || async { Err(Error::GRPCTimeout) })
+            .await;
+        assert!(failed.is_err()); // Leaves a dropped entry first in the order
+        for type_id in [2, 3, 4] {
+            get(&cache, type_id, &fetches).await;
+        }
+        assert_eq!(cache.stats().size, 2);
+        get(&cache, 4, &fetches).await;
+        get(&cache, 3, &fetches).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+        get(&cache, 2, &fetches).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 4);
+        assert_eq!(cache.stats().size, 2);
+    }
+
+    #[tokio::test]
+    async fn capacity_zero_holds_one_entry() {
+        let cache: Cache = Cache::new(Duration::from_secs(60), 0);
+        let fetches: AtomicUsize = AtomicUsize::new(0);
+        get(&cache, 1, &fetches).await;
+        get(&cache, 1, &fetches).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        get(&cache, 2, &fetches).await;
+        assert_eq!(cache.stats().size, 1);
+        get(&cache, 1, &fetches).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,689 @@
+use crate::{
+    {Location, ItemName, TypeId},
+    pricing::{PricingModel, ReprocessYield},
+    fees::FeeProfile,
+    freight::FreightRule,
+    error::Error,
+};
+
+use std::{
+    collections::{HashMap, BTreeMap, BTreeSet},
+    fmt::Debug,
+    fs,
+};
+
+use serde::{Serialize, Deserialize};
+use serde_json;
+use sha2::{Sha256, Digest};
+
+// How many bytes of the config's digest its version keeps
+const VERSION_BYTES: usize = 8;
+// How many names a rejected item is offered instead at most
+const MAX_SUGGESTIONS: usize = 3;
+
+// The pricing rules, location -> item -> PricingModel, and the fees and
+// freight taken off the prices at each location, loaded at startup
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default, alias = "version", skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>, // for people, it does not change the version
+    #[serde(skip)]
+    pub version: String, // label@digest of the canonical rules, see read
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub aliases: HashMap<ItemName, ItemName>, // old or alternate name -> name
+    pub locations: HashMap<Location, HashMap<ItemName, PricingModel>>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub fees: HashMap<Location, FeeProfile>, // locations without are priced gross
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub freight: HashMap<Location, FreightRule>, // without, priced at the hub
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub volumes: HashMap<ItemName, f64>, // packaged m3 per unit
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub reprocess_yields: HashMap<Location, ReprocessYield>,
+    #[serde(skip)]
+    names: HashMap<Location, HashMap<String, ItemName>>, // normalized -> name
+}
+
+// The rules as the digest hashes them, sorted into BTreeMaps so that neither
+// HashMap iteration order nor serde_json's map type can change the digest
+#[derive(Serialize)]
+struct Canonical<'c> {
+    aliases: BTreeMap<&'c str, &'c ItemName>,
+    locations: BTreeMap<&'c str, BTreeMap<&'c str, &'c PricingModel>>,
+    fees: BTreeMap<&'c str, &'c FeeProfile>,
+    freight: BTreeMap<&'c str, &'c FreightRule>,
+    volumes: BTreeMap<&'c str, &'c f64>,
+    reprocess_yields: BTreeMap<&'c str, &'c ReprocessYield>,
+}
+
+impl Config {
+    // Reads, parses and validates the config file at path
+    pub fn load(path: &str) -> Result<Config, Error> {
+        let config: Config = Config::read(path)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Reads and parses without validating, parse errors carry the line and
+    // column they occurred at
+    pub fn read(path: &str) -> Result<Config, Error> {
+        let s: String = fs::read_to_string(path)
+            .map_err(|e| Error::ConfigReadError(e))?;
+        let mut config: Config = serde_json::from_str(&s)
+            .map_err(|e| Error::ConfigParseError(e))?;
+        let digest: String = config.digest()?;
+        config.version = match &config.label {
+            Some(label) => format!("{}@{}", label, digest),
+            None => digest,
+        };
+        config.index();
+        Ok(config)
+    }
+
+    // Hashes every rule serialized with every map's keys sorted and no
+    // whitespace, so that only changes to the rules change the digest
+    fn digest(&self) -> Result<String, Error> {
+        let canonical: String = serde_json::to_string(&Canonical {
+            aliases: sorted(&self.aliases),
+            locations: self.locations
+                .iter()
+                .map(|(location, lmap)| (location.as_str(), sorted(lmap)))
+                .collect(),
+            fees: sorted(&self.fees),
+            freight: sorted(&self.freight),
+            volumes: sorted(&self.volumes),
+            reprocess_yields: sorted(&self.reprocess_yields),
+        })
+            .map_err(|e| Error::SerializationError(e))?;
+        Ok(Sha256::digest(canonical.as_bytes())
+            .iter()
+            .take(VERSION_BYTES)
+            .map(|b| format!("{:02x}", b))
+            .collect()
+        )
+    }
+
+    pub fn get(&self, location: &str, item: &str) -> Option<&PricingModel> {
+        self.locations
+            .get(location)
+            .and_then(|lmap| lmap.get(item))
+    }
+
+    // Looks item up by its exact name, then by its normalized name or alias,
+    // returning the name it is priced under at location with its model
+    pub fn resolve(
+        &self,
+        location: &str,
+        item: &str,
+    ) -> Option<(&str, &PricingModel)> {
+        let lmap: &HashMap<ItemName, PricingModel> = self
+            .locations
+            .get(location)?;
+        let name: &str = match lmap.contains_key(item) {
+            true => item,
+            false => self
+                .names
+                .get(location)?
+                .get(&normalize(item))?
+                .as_str(),
+        };
+        lmap
+            .get_key_value(name)
+            .map(|(name, pricing_model)| (name.as_str(), pricing_model))
+    }
+
+    // Returns the names priced at location closest to item by edit distance,
+    // closest first, leaving out any too far off to be what was meant
+    pub fn suggestions(&self, location: &str, item: &str) -> Vec<ItemName> {
+        let item: String = normalize(item);
+        let max_distance: usize = item.chars().count() / 3 + 1;
+        let mut closest: HashMap<&str, usize> = HashMap::new();
+        for (normalized, name) in self
+            .names
+            .get(location)
+            .into_iter()
+            .flatten()
+        {
+            let distance: usize = edit_distance(&item, normalized);
+            if distance > max_distance {
+                continue;
+            }
+            let entry: &mut usize = closest
+                .entry(name.as_str())
+                .or_insert(distance);
+            *entry = distance.min(*entry);
+        }
+        let mut closest: Vec<(usize, &str)> = closest
+            .into_iter()
+            .map(|(name, distance)| (distance, name))
+            .collect();
+        closest.sort();
+        closest
+            .into_iter()
+            .take(MAX_SUGGESTIONS)
+            .map(|(_, name)| name.to_string())
+            .collect()
+    }
+
+    pub fn models(&self) -> impl Iterator<Item = &PricingModel> {
+        self.locations
+            .values()
+            .flat_map(|lmap| lmap.values())
+    }
+
+    // Returns every problem found walking every location and model, sorted,
+    // as <path>: <problem>. Unlike validate, most of these still load.
+    pub fn lint(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        let mut names: BTreeMap<TypeId, BTreeSet<&str>> = BTreeMap::new();
+        for (location, lmap) in self.locations.iter() {
+            if lmap
+                .values()
+                .all(|pm| pm == &PricingModel::Rejected)
+            {
+                problems.push(format!(
+                    "locations.{}: no item can be accepted here",
+                    location,
+                ));
+            }
+            for (item, pricing_model) in lmap.iter() {
+                for problem in pricing_model.lint(self) {
+                    problems.push(format!(
+                        "locations.{}.{}: {}",
+                        location,
+                        item,
+                        problem,
+                    ));
+                }
+                if let Some(type_id) = pricing_model.type_id() {
+                    names
+                        .entry(type_id)
+                        .or_default()
+                        .insert(item.as_str());
+                }
+            }
+        }
+        problems.extend(self.yield_problems());
+        problems.extend(self.fee_problems());
+        problems.extend(self.unreachable_problems());
+        for (alias, name) in self.aliases.iter() {
+            if !self
+                .locations
+                .values()
+                .any(|lmap| lmap.contains_key(name))
+            {
+                problems.push(format!(
+                    "aliases.{}: {} is not priced at any location",
+                    alias,
+                    name,
+                ));
+            }
+            if self
+                .names
+                .values()
+                .any(|names| names
+                    .get(&normalize(alias))
+                    .map_or(false, |priced| priced != name)
+                )
+            {
+                problems.push(format!(
+                    "aliases.{}: shadowed by an item or alias of the same name",
+                    alias,
+                ));
+            }
+        }
+        problems.extend(self.name_problems());
+        for (type_id, names) in names.iter() {
+            if names.len() > 1 {
+                problems.push(format!(
+                    "type_id {}: priced under different names {}",
+                    type_id,
+                    names
+                        .iter()
+                        .copied()
+                        .collect::<Vec<&str>>()
+                        .join(", "),
+                ));
+            }
+        }
+        problems.sort();
+        problems
+    }
+
+    // Lists every item added, removed, or whose model, market or modifier
+    // changed from self to other, then every alias, fee, freight, volume and
+    // yield changed, sorted, as <path>: <change>
+    pub fn diff(&self, other: &Config) -> Vec<String> {
+        let mut changes: Vec<String> = Vec::new();
+        let keys: BTreeSet<(&str, &str)> = self
+            .keys()
+            .chain(other.keys())
+            .collect();
+        for (location, item) in keys {
+            let path: String = format!("locations.{}.{}", location, item);
+            let (old, new) = match (
+                self.get(location, item),
+                other.get(location, item),
+            ) {
+                (Some(old), Some(new)) if old == new => continue,
+                (Some(old), Some(new)) => (old, new),
+                (None, Some(_)) => {
+                    changes.push(format!("{}: added", path));
+                    continue;
+                },
+                (Some(_), None) => {
+                    changes.push(format!("{}: removed", path));
+                    continue;
+                },
+                (None, None) => continue,
+            };
+            let mut changed: bool = false;
+            let (old_kind, new_kind) = (
+                old.price_source(self).kind,
+                new.price_source(other).kind,
+            );
+            if old_kind != new_kind {
+                changes.push(format!(
+                    "{}: model {:?} -> {:?}",
+                    path,
+                    old_kind,
+                    new_kind,
+                ));
+                changed = true;
+            }
+            if old.markets() != new.markets() {
+                changes.push(format!(
+                    "{}: markets {:?} -> {:?}",
+                    path,
+                    old.markets(),
+                    new.markets(),
+                ));
+                changed = true;
+            }
+            if old.price_mods() != new.price_mods() {
+                changes.push(format!(
+                    "{}: modifiers {:?} -> {:?}",
+                    path,
+                    old.price_mods(),
+                    new.price_mods(),
+                ));
+                changed = true;
+            }
+            if !changed {
+                changes.push(format!("{}: other parameters changed", path));
+            }
+        }
+        changes.extend(diff_table("aliases", &self.aliases, &other.aliases));
+        changes.extend(diff_table("fees", &self.fees, &other.fees));
+        changes.extend(diff_table("freight", &self.freight, &other.freight));
+        changes.extend(diff_table("volumes", &self.volumes, &other.volumes));
+        changes.extend(diff_table(
+            "reprocess_yields",
+            &self.reprocess_yields,
+            &other.reprocess_yields,
+        ));
+        changes
+    }
+
+    // Indexes every name and alias priced at each location by its normalized
+    // form, names taking precedence over aliases
+    fn index(&mut self) {
+        self.names = self
+            .locations
+            .iter()
+            .map(|(location, lmap)| {
+                let mut names: HashMap<String, ItemName> = self
+                    .aliases
+                    .iter()
+                    .filter(|(_, name)| lmap.contains_key(*name))
+                    .map(|(alias, name)| (normalize(alias), name.clone()))
+                    .collect();
+                for name in lmap.keys() {
+                    names.insert(normalize(name), name.clone());
+                }
+                (location.clone(), names)
+            })
+            .collect();
+    }
+
+    fn keys(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.locations
+            .iter()
+            .flat_map(|(location, lmap)| lmap
+                .keys()
+                .map(move |item| (location.as_str(), item.as_str()))
+            )
+    }
+
+    fn yield_problems(&self) -> Vec<String> {
+        self.reprocess_yields
+            .iter()
+            .filter(|(_, y)| !(0.0..=1.0).contains(&y.total()))
+            .map(|(location, y)| format!(
+                "reprocess_yields.{}: yield {} is outside 0.0 - 1.0",
+                location,
+                y.total(),
+            ))
+            .collect()
+    }
+
+    // Fees, freight and yields of locations that are not configured, which
+    // nothing prices at, except yields a Reprocessing model reprocesses at
+    fn unreachable_problems(&self) -> Vec<String> {
+        let reprocessed_at: BTreeSet<&str> = self
+            .models()
+            .flat_map(|pm| pm.reprocessed_at())
+            .collect();
+        let unreachable = |table: &str, location: &str| format!(
+            "{}.{}: not a configured location",
+            table,
+            location,
+        );
+        let mut problems: Vec<String> = Vec::new();
+        for location in self.fees.keys() {
+            if !self.locations.contains_key(location) {
+                problems.push(unreachable("fees", location));
+            }
+        }
+        for location in self.freight.keys() {
+            if !self.locations.contains_key(location) {
+                problems.push(unreachable("freight", location));
+            }
+        }
+        for location in self.reprocess_yields.keys() {
+            if !self.locations.contains_key(location)
+                && !reprocessed_at.contains(location.as_str())
+            {
+                problems.push(unreachable("reprocess_yields", location));
+            }
+        }
+        problems
+    }
+
+    // Fees and collateral are fractions of the gross price, so 3.6 meant as
+    // 0.036 would reject every item and a negative one would inflate them
+    fn fee_problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        let mut check = |table: &str, location: &str, name: &str, f: f64| {
+            if !(0.0..=1.0).contains(&f) {
+                problems.push(format!(
+                    "{}.{}: {} {} is outside 0.0 - 1.0",
+                    table,
+                    location,
+                    name,
+                    f,
+                ));
+            }
+        };
+        for (location, profile) in sorted(&self.fees) {
+            check("fees", location, "sales_tax", profile.sales_tax);
+            check("fees", location, "broker_fee", profile.broker_fee);
+            if let Some(relist_buffer) = profile.relist_buffer {
+                check("fees", location, "relist_buffer", relist_buffer);
+            }
+        }
+        for (location, rule) in sorted(&self.freight) {
+            check("freight", location, "collateral", rule.collateral);
+        }
+        problems
+    }
+
+    // Names or aliases that normalize alike would resolve to whichever the
+    // index happened to insert last
+    fn name_problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        for (location, lmap) in self.locations.iter() {
+            let mut names: Vec<&str> = lmap
+                .keys()
+                .map(|name| name.as_str())
+                .collect();
+            names.sort();
+            let mut seen: HashMap<String, &str> = HashMap::new();
+            for name in names {
+                if let Some(other) = seen.insert(normalize(name), name) {
+                    problems.push(format!(
+                        "locations.{}: {} and {} are the same name normalized",
+                        location,
+                        other,
+                        name,
+                    ));
+                }
+            }
+        }
+        let mut aliases: Vec<(&str, &str)> = self
+            .aliases
+            .iter()
+            .map(|(alias, name)| (alias.as_str(), name.as_str()))
+            .collect();
+        aliases.sort();
+        let mut seen: HashMap<String, (&str, &str)> = HashMap::new();
+        for (alias, name) in aliases {
+            match seen.insert(normalize(alias), (alias, name)) {
+                Some((other, other_name)) if other_name != name => {
+                    problems.push(format!(
+                        "aliases.{}: the same alias normalized as {}, for {} instead of {}",
+                        alias,
+                        other,
+                        name,
+                        other_name,
+                    ));
+                },
+                _ => (),
+            }
+        }
+        problems
+    }
+
+    // Returns the first model that prices from items that are not configured,
+    // that contains itself or whose parameters are out of range, as
+    // locations.<location>.<item>: <reason>, or the first yield, fee or
+    // collateral out of range or names that normalize alike
+    fn validate(&self) -> Result<(), Error> {
+        for (location, lmap) in self.locations.iter() {
+            for (item, pricing_model) in lmap.iter() {
+                if let Err(reason) = pricing_model.check(self) {
+                    return Err(Error::ConfigInvalid(format!(
+                        "locations.{}.{}: {}",
+                        location,
+                        item,
+                        reason,
+                    )));
+                }
+            }
+        }
+        match self
+            .yield_problems()
+            .into_iter()
+            .chain(self.fee_problems())
+            .chain(self.name_problems())
+            .next()
+        {
+            Some(problem) => Err(Error::ConfigInvalid(problem)),
+            None => Ok(()),
+        }
+    }
+}
+
+// Lists every key of a keyed table added, removed or changed from old to new,
+// sorted, as <table>.<key>: <change>
+fn diff_table<V: PartialEq + Debug>(
+    table: &str,
+    old: &HashMap<String, V>,
+    new: &HashMap<String, V>,
+) -> Vec<String> {
+    let keys: BTreeSet<&str> = old
+        .keys()
+        .chain(new.keys())
+        .map(|key| key.as_str())
+        .collect();
+    keys
+        .into_iter()
+        .filter_map(|key| match (old.get(key), new.get(key)) {
+            (Some(o), Some(n)) if o == n => None,
+            (Some(o), Some(n)) => Some(format!("{}.{}: {:?} -> {:?}", table, key, o, n)),
+            (None, Some(_)) => Some(format!("{}.{}: added", table, key)),
+            (Some(_), None) => Some(format!("{}.{}: removed", table, key)),
+            (None, None) => None,
+        })
+        .collect()
+}
+
+fn sorted<V>(map: &HashMap<String, V>) -> BTreeMap<&str, &V> {
+    map
+        .iter()
+        .map(|(k, v)| (k.as_str(), v))
+        .collect()
+}
+
+// Trims, collapses runs of whitespace and lowercases, so that names typed or
+// pasted differently compare equal
+fn normalize(name: &str) -> String {
+    name
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+// Levenshtein distance in chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b
+        .chars()
+        .collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal: usize = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above: usize = row[j + 1];
+            row[j + 1] = match ca == *cb {
+                true => diagonal,
+                false => 1 + diagonal.min(above).min(row[j]),
+            };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"{
+        "label": "test",
+        "aliases": {"Trit": "Tritanium", "Pye": "Pyerite"},
+        "locations": {"L": {
+            "Tritanium": {"AdjustedPriceModel": {"type_id": 34, "modifier": 0.9, "description": ""}},
+            "Pyerite": {"AdjustedPriceModel": {"type_id": 35, "modifier": 0.9, "description": ""}}
+        }},
+        "fees": {"L": {"sales_tax": 0.036, "broker_fee": 0.015, "relist_buffer": 0.0}},
+        "volumes": {"Tritanium": 0.01, "Pyerite": 0.01}
+    }"#;
+
+    fn parse(s: &str) -> Config {
+        let mut config: Config = serde_json::from_str(s).unwrap();
+        config.index();
+        config
+    }
+
+    #[test]
+    fn normalize_collapses_whitespace_and_case() {
+        assert_eq!(normalize("  Large   Skill\tInjector "), "large skill injector");
+        assert_eq!(normalize("PLEX"), normalize("plex"));
+        assert_eq!(normalize(""), "");
+    }
+
+    #[test]
+    fn edit_distance_counts_chars() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("tritanium", "tritanium"), 0);
+        assert_eq!(edit_distance("tritanium", "tritainum"), 2);
+        assert_eq!(edit_distance("mégacyte", "megacyte"), 1);
+    }
+
+    #[test]
+    fn digest_ignores_order_and_whitespace() {
+        let config: Config = parse(RULES);
+        let reordered: Config = parse(r#"{"volumes": {"Pyerite": 0.01, "Tritanium": 0.01},
+            "fees": {"L": {"relist_buffer": 0.0, "broker_fee": 0.015, "sales_tax": 0.036}},
+            "locations": {"L": {
+            "Pyerite": {"AdjustedPriceModel": {"description": "", "modifier": 0.9, "type_id": 35}},
+            "Tritanium": {"AdjustedPriceModel": {"type_id": 34, "modifier": 0.9, "description": ""}}}},
+            "aliases": {"Pye": "Pyerite", "Trit": "Tritanium"}, "version": "other"}"#);
+        for _ in 0..8 { // HashMap iteration order differs between instances
+            assert_eq!(config.digest().unwrap(), parse(RULES).digest().unwrap());
+        }
+        assert_eq!(config.digest().unwrap(), reordered.digest().unwrap());
+    }
+
+    #[test]
+    fn digest_changes_with_every_table() {
+        let digest: String = parse(RULES).digest().unwrap();
+        for (old, new) in [
+            (r#""Trit": "Tritanium""#, r#""Trito": "Tritanium""#),
+            (r#""modifier": 0.9, "description": ""}}
+        }}"#, r#""modifier": 0.8, "description": ""}}
+        }}"#),
+            (r#""sales_tax": 0.036"#, r#""sales_tax": 0.045"#),
+            (r#""Pyerite": 0.01}"#, r#""Pyerite": 0.02}"#),
+        ] {
+            assert!(RULES.contains(old));
+            assert_ne!(parse(&RULES.replace(old, new)).digest().unwrap(), digest);
+        }
+    }
+
+    #[test]
+    fn resolves_normalized_names_and_aliases() {
+        let config: Config = parse(RULES);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.resolve("L", " tritanium").map(|(name, _)| name), Some("Tritanium"));
+        assert_eq!(config.resolve("L", "PYE").map(|(name, _)| name), Some("Pyerite"));
+        assert_eq!(config.resolve("L", "Tritanum"), None);
+        assert_eq!(config.suggestions("L", "Tritanum"), vec!["Tritanium".to_string()]);
+
+        let collides: Config = parse(&RULES.replace(r#""Pye""#, r#""TRIT""#));
+        assert!(collides.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_fees_that_are_not_fractions() {
+        assert!(parse(RULES).fee_problems().is_empty());
+        for (old, new) in [
+            (r#""sales_tax": 0.036"#, r#""sales_tax": 3.6"#),
+            (r#""broker_fee": 0.015"#, r#""broker_fee": -0.015"#),
+            (r#""relist_buffer": 0.0"#, r#""relist_buffer": 1.5"#),
+        ] {
+            assert!(parse(&RULES.replace(old, new)).validate().is_err());
+        }
+        let collateral: Config = parse(&RULES.replace(
+            r#""volumes""#,
+            r#""freight": {"L": {"isk_per_m3": 800.0, "collateral": 2.0, "minimum_fee": 0.0}}, "volumes""#,
+        ));
+        assert_eq!(
+            collateral.fee_problems(),
+            vec!["freight.L: collateral 2 is outside 0.0 - 1.0".to_string()],
+        );
+    }
+
+    #[test]
+    fn lints_tables_of_unconfigured_locations() {
+        assert!(parse(RULES).unreachable_problems().is_empty());
+        let config: Config = parse(&RULES.replace(
+            r#""volumes""#,
+            r#""freight": {"M": {"isk_per_m3": 800.0, "collateral": 0.01, "minimum_fee": 0.0}},
+            "reprocess_yields": {"N": {"structure": 0.5, "skills": 0.0, "implant": 0.0}},
+            "volumes""#,
+        ).replace(r#""fees": {"L""#, r#""fees": {"K""#));
+        let problems: Vec<String> = config.lint();
+        for problem in [
+            "fees.K: not a configured location",
+            "freight.M: not a configured location",
+            "reprocess_yields.N: not a configured location",
+        ] {
+            assert!(problems.contains(&problem.to_string()), "{:?}", problems);
+        }
+    }
+}
@@ -11,6 +11,8 @@ pub enum Error {
     HyperRequestBodyError(Box<dyn std::error::Error + Send + Sync>),
     GRPCConnectionError(tonic::transport::Error),
     GRPCStatus(tonic::Status),
+    GRPCTimeout,
+    AppraisalDeadline,
     SerializationError(serde_json::Error),
     DeserializationError(serde_json::Error),
     StdinError(std::io::Error),
@@ -22,6 +24,9 @@ pub enum Error {
     ParserPipeError(std::io::Error),
     ParserRuntimeError(String),
     ParserDeserializationError(serde_json::Error),
+    ConfigReadError(std::io::Error),
+    ConfigParseError(serde_json::Error),
+    ConfigInvalid(String),
 
 }
 
@@ -0,0 +1,31 @@
+use crate::{
+    config::Config,
+    response::Deduction,
+};
+
+use serde::{Serialize, Deserialize};
+
+// Fees paid when relisting at the hub that a location's goods are hauled to,
+// as fractions of the gross price
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FeeProfile {
+    pub sales_tax: f64,
+    pub broker_fee: f64,
+    pub relist_buffer: Option<f64>, // covers undercutting and order updates
+}
+
+// Returns the per unit deductions from gross at location, which is priced
+// gross if config has no fee profile for it
+pub fn deductions(config: &Config, location: &str, gross: f64) -> Vec<Deduction> {
+    let profile: &FeeProfile = match config.fees.get(location) {
+        Some(profile) => profile,
+        None => return vec![],
+    };
+    let mut deductions: Vec<Deduction> = Vec::with_capacity(3);
+    deductions.push(Deduction::new("sales_tax", gross * profile.sales_tax));
+    deductions.push(Deduction::new("broker_fee", gross * profile.broker_fee));
+    if let Some(relist_buffer) = profile.relist_buffer {
+        deductions.push(Deduction::new("relist_buffer", gross * relist_buffer));
+    }
+    deductions
+}
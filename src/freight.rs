@@ -0,0 +1,48 @@
+use crate::{
+    config::Config,
+    response::Deduction,
+};
+
+use serde::{Serialize, Deserialize};
+
+// Cost of hauling a location's goods to the hub they are priced at
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FreightRule {
+    pub isk_per_m3: f64,
+    pub collateral: f64, // fraction of the gross price
+    pub minimum_fee: f64, // per contract, which is per appraisal
+}
+
+// Packaged volume in m3 per unit, items config has no volume for haul for free
+pub fn volume(config: &Config, item: &str) -> f64 {
+    config
+        .volumes
+        .get(item)
+        .copied()
+        .unwrap_or(0.0)
+}
+
+pub fn minimum_fee(config: &Config, location: &str) -> f64 {
+    config
+        .freight
+        .get(location)
+        .map(|rule| rule.minimum_fee)
+        .unwrap_or(0.0)
+}
+
+// Returns the per unit freight deduction for item at location, which is
+// priced at the hub if config has no freight rule for it
+pub fn deductions(
+    config: &Config,
+    location: &str,
+    item: &str,
+    gross: f64,
+) -> Vec<Deduction> {
+    match config.freight.get(location) {
+        Some(rule) => vec![Deduction::new(
+            "freight",
+            volume(config, item) * rule.isk_per_m3 + gross * rule.collateral,
+        )],
+        None => vec![],
+    }
+}
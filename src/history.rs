@@ -0,0 +1,136 @@
+use crate::{
+    {TypeId, Client, is_error},
+    config::Config,
+    rpc::{Req, Rep, plan, get_reps},
+    error::Error,
+    proto::*,
+};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use firestore::FirestoreDb;
+use serde::{Serialize, Deserialize};
+
+const SNAPSHOT_COLLECTION: &str = "price_snapshots";
+const SECS_PER_DAY: u64 = 86400;
+
+// The best price of one side of one market's book at one point in time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub type_id: TypeId,
+    pub market: String,
+    pub buy: bool,
+    pub price: f64,
+    pub timestamp: u64,
+}
+
+// Asks for the snapshot prices of the last `days` days
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SnapshotsReq {
+    pub type_id: TypeId,
+    pub market: String,
+    pub buy: bool,
+    pub days: u32,
+}
+
+// Records a snapshot of every book that config prices from, returning how
+// many were recorded
+pub async fn record_snapshots(
+    db: &FirestoreDb,
+    client: Client,
+    config: &Config,
+) -> Result<usize, Error> {
+    let reqs: Vec<Req> = plan(config
+        .models()
+        .flat_map(|pm| pm.snapshot_reqs(config))
+        .map(Req::MarketOrders)
+    );
+
+    let timestamp: u64 = now();
+    let mut recorded: usize = 0;
+    for (req, rep) in get_reps(client, db, None, None, reqs).await {
+        let (req, rep): (MarketOrdersReq, MarketOrdersRep) = match (req, rep) {
+            (Req::MarketOrders(req), Rep::MarketOrders(rep)) => (req, rep),
+            _ => continue,
+        };
+        let best: Option<f64> = rep
+            .market_orders
+            .iter()
+            .map(|o| o.price)
+            .filter(|p| p.is_finite())
+            .reduce(|p1, p2| match req.buy {
+                true => p1.max(p2),
+                false => p1.min(p2),
+            });
+        let snapshot: Snapshot = match best {
+            Some(price) => Snapshot {
+                type_id: req.type_id,
+                market: req.market,
+                buy: req.buy,
+                price: price,
+                timestamp: timestamp,
+            },
+            None => continue, // Nothing to record when there are no orders
+        };
+        match db
+            .fluent()
+            .insert()
+            .into(SNAPSHOT_COLLECTION)
+            .document_id(snapshot.document_id())
+            .object(&snapshot)
+            .execute::<Snapshot>()
+            .await
+        {
+            Err(e) if is_error(&e) => return Err(Error::FirestoreInsertError(e)),
+            _ => recorded += 1,
+        }
+    }
+    Ok(recorded)
+}
+
+// Returns the snapshot prices matching req, oldest first
+pub async fn snapshot_prices(
+    db: &FirestoreDb,
+    req: &SnapshotsReq,
+) -> Result<Vec<f64>, Error> {
+    let since: u64 = now().saturating_sub(req.days as u64 * SECS_PER_DAY);
+    let mut snapshots: Vec<Snapshot> = db
+        .fluent()
+        .select()
+        .from(SNAPSHOT_COLLECTION)
+        .filter(|q| q.for_all([
+            q.field("type_id").eq(req.type_id),
+            q.field("market").eq(req.market.as_str()),
+            q.field("buy").eq(req.buy),
+            q.field("timestamp").greater_than_or_equal(since),
+        ]))
+        .obj()
+        .query()
+        .await
+        .map_err(|e| Error::FirestoreSelectError(e))?;
+    snapshots.sort_by_key(|s| s.timestamp);
+    Ok(snapshots
+        .into_iter()
+        .map(|s| s.price)
+        .collect()
+    )
+}
+
+impl Snapshot {
+    fn document_id(&self) -> String {
+        format!(
+            "{}-{}-{}-{}",
+            self.type_id,
+            self.market,
+            self.buy,
+            self.timestamp,
+        )
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Now < UnixEpoch?")
+        .as_secs()
+}
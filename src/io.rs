@@ -1,6 +1,6 @@
 use crate::{
     pricing::PricingModel,
-    static_map::PM_MAP,
+    config::Config,
     parse::parse,
     error::Error,
     item::Item,
@@ -31,9 +31,14 @@ pub fn read_firestore_token() -> Result<String, Error> {
     Ok(var("BBBE_GCPTOKEN")?)
 }
 
+pub fn read_config_path() -> Result<String, Error> {
+    Ok(var("BBBE_CONFIG")?)
+}
+
 pub enum ParsedInput<'s> {
     Items((Vec<(Item, PricingModel)>, &'s str)),
     Hash(Hash<'s>),
+    Audit(Hash<'s>),
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -41,6 +46,8 @@ pub enum ParsedInput<'s> {
 enum Input<'s> {
     #[serde(bound(deserialize = "HashInput<'s>: Deserialize<'de>"))]
     HashInput(HashInput<'s>),
+    #[serde(bound(deserialize = "AuditInput<'s>: Deserialize<'de>"))]
+    AuditInput(AuditInput<'s>),
     #[serde(bound(deserialize = "ItemInput<'s>: Deserialize<'de>"))]
     ItemInput(ItemInput<'s>),
 }
@@ -50,6 +57,11 @@ struct HashInput<'s> {
     hash: &'s str, // This needs to be &str instead of Hash because of serde
 }
 
+#[derive(Debug, Clone, Deserialize)]
+struct AuditInput<'s> {
+    audit: &'s str, // The hash of the audited quote
+}
+
 #[derive(Debug, Clone, Deserialize)]
 struct ItemInput<'s> {
     location: &'s str,
@@ -66,21 +78,31 @@ enum ItemInputItems {
 }
 
 impl<'s> ParsedInput<'s> {
-    pub fn from_str(s: &'s str) -> Result<ParsedInput<'s>, Error> {
+    pub fn from_str(
+        s: &'s str,
+        config: &Config,
+    ) -> Result<ParsedInput<'s>, Error> {
         let input: Input<'s> = serde_json::from_str(s)
             .map_err(|e| Error::DeserializationError(e))?;
-        ParsedInput::try_from_input(input)
+        ParsedInput::try_from_input(input, config)
     }
 
-    pub fn from_slice(b: &'s [u8]) -> Result<ParsedInput<'s>, Error> {
+    pub fn from_slice(
+        b: &'s [u8],
+        config: &Config,
+    ) -> Result<ParsedInput<'s>, Error> {
         let input: Input<'s> = serde_json::from_slice(b)
             .map_err(|e| Error::DeserializationError(e))?;
-        ParsedInput::try_from_input(input)
+        ParsedInput::try_from_input(input, config)
     }
 
-    fn try_from_input(input: Input<'s>) -> Result<ParsedInput<'s>, Error> {
+    fn try_from_input(
+        input: Input<'s>,
+        config: &Config,
+    ) -> Result<ParsedInput<'s>, Error> {
         let (location, items): (&str, Vec<Item>) = match input {
             Input::HashInput(h) => return Ok(ParsedInput::Hash(h.hash)),
+            Input::AuditInput(a) => return Ok(ParsedInput::Audit(a.audit)),
             Input::ItemInput(i) => match i.items {
                 ItemInputItems::Json(v) => (
                     i.location,
@@ -97,12 +119,18 @@ impl<'s> ParsedInput<'s> {
             items.len()
         );
         for item in items.into_iter() {
-            let entry: (Item, PricingModel) = match PM_MAP
-                .get(location) 
-                .map(|lmap| lmap.get(&item.name))
+            // Matched items are renamed to the name they are priced under
+            let entry: (Item, PricingModel) = match config
+                .resolve(location, &item.name)
             {
-                Some(Some(pricing_model)) => (item, pricing_model.clone()),
-                Some(None) | None => (item, PricingModel::Rejected),
+                Some((name, pricing_model)) => (
+                    Item {
+                        name: name.to_string(),
+                        quantity: item.quantity,
+                    },
+                    pricing_model.clone(),
+                ),
+                None => (item, PricingModel::Rejected),
             };
             inner.push(entry);
         }
@@ -1,46 +1,64 @@
 mod proto {
     tonic::include_proto!("weve_esi_proto");
 }
-mod static_map;
 mod response;
 mod pricing;
+mod rpc;
 mod error;
 mod parse;
 mod item;
 mod io;
+mod fees;
+mod freight;
+mod history;
+mod cache;
+mod source;
+mod audit;
+mod config;
 
 pub use response::Response;
-pub use io::ParsedInput;
+pub use io::{ParsedInput, read_config_path};
 pub use error::Error;
+pub use history::record_snapshots;
+pub use cache::{Cache, CacheStats};
+pub use source::{PriceSource, ModelKind, Component};
+pub use audit::{Audit, audit_from_hash};
+pub use config::Config;
 
 use pricing::{PricingModel, Price};
+use rpc::{Req, Reps, plan, get_reps, limits};
 use item::Item;
 
-use futures::stream::{TryStreamExt, futures_unordered::FuturesUnordered};
 use firestore::{*, errors::*};
 use gcloud_sdk;
+use tokio::time::Instant;
 
 pub type Client = proto::weve_market_client::WeveMarketClient<
     tonic::transport::Channel
 >;
-type Location = &'static str;
-type Market = &'static str;
-type PriceSource = String;
+type Location = String;
+type Market = String;
 type Hash<'s> = &'s str;
 type ItemName = String;
 type Quantity = f64;
 type PriceMod = f64;
 type TypeId = i32;
+type SystemId = i32;
+// An item priced, or why it could not be
+type Priced = (Item, PricingModel, Result<(Price, PriceSource), String>);
 
 pub async fn response_from_items(
     items: Vec<(Item, PricingModel)>,
     location: &str,
     db: &FirestoreDb,
     client: &Client,
+    cache: Option<&Cache>,
+    config: &Config,
 ) -> Result<Response, Error> {
     let mut response: Response = Response::with_capacity(
         items.len(),
         location.to_string(),
+        config.version.clone(),
     );
 
     let mut return_empty: bool = true;
@@ -52,23 +70,35 @@ pub async fn response_from_items(
     }
     if return_empty {
         for item in items {
-            response.push(item.0, Price::Rejected, item.1.price_source());
+            response.push(
+                config,
+                item.0,
+                Price::Rejected,
+                item.1.price_source(config),
+            );
         }
+        response.with_suggestions(config);
         return Ok(response);
     }
 
-    let mut stream = items
-        .into_iter()
-        .map(|(item, model)| get_price(item, model, client.clone()))
-        .collect::<FuturesUnordered<_>>();
-    while let Some((item, price, price_source)) = stream
-        .try_next()
-        .await?
-    {
-        response.push(item, price, price_source);
-    }
+    let (priced, audit): (Vec<Priced>, Option<Audit>) = get_prices(
+        items,
+        client.clone(),
+        db,
+        cache,
+        config,
+    ).await;
+    push_priced(&mut response, priced, config);
+    response.with_freight_minimum(config);
+    response.with_suggestions(config);
     response.sort();
+    if response.partial {
+        return Ok(response); // Not final, so it is not stored
+    }
     let hash_key: &str = response.with_hash_key();
+    if let Some(audit) = audit {
+        audit.store(db, hash_key).await?;
+    }
 
     match db
         .fluent()
@@ -86,10 +116,12 @@ pub async fn response_from_items(
 pub async fn shell_response_from_items(
     items: Vec<(Item, PricingModel)>,
     location: &str,
+    config: &Config,
 ) -> Result<Response, Error> {
     let mut response: Response = Response::with_capacity(
         items.len(),
         location.to_string(),
+        config.version.clone(),
     );
 
     let mut return_empty: bool = true;
@@ -101,29 +133,41 @@ pub async fn shell_response_from_items(
     }
     if return_empty {
         for item in items {
-            response.push(item.0, Price::Rejected, item.1.price_source());
+            response.push(
+                config,
+                item.0,
+                Price::Rejected,
+                item.1.price_source(config),
+            );
         }
+        response.with_suggestions(config);
         return Ok(response);
     }
 
     let db = get_db(); // Unawaited Future
     let client: Client = get_client().await?;
+    let db: FirestoreDb = db.await?;
 
-    let mut stream = items
-        .into_iter()
-        .map(|(item, model)| get_price(item, model, client.clone()))
-        .collect::<FuturesUnordered<_>>();
-    while let Some((item, price, price_source)) = stream
-        .try_next()
-        .await?
-    {
-        response.push(item, price, price_source);
-    }
+    let (priced, audit): (Vec<Priced>, Option<Audit>) = get_prices(
+        items,
+        client,
+        &db,
+        None,
+        config,
+    ).await;
+    push_priced(&mut response, priced, config);
+    response.with_freight_minimum(config);
+    response.with_suggestions(config);
     response.sort();
+    if response.partial {
+        return Ok(response); // Not final, so it is not stored
+    }
     let hash_key: &str = response.with_hash_key();
+    if let Some(audit) = audit {
+        audit.store(&db, hash_key).await?;
+    }
 
     match db
-        .await?
         .fluent()
         .insert()
         .into("hash_cache")
@@ -147,7 +191,11 @@ pub async fn response_from_hash(
         .obj()
         .one(hash_cache_key)
         .await
-        .map(|o| o.unwrap_or(Response::with_capacity(0, "".to_string())))
+        .map(|o| o.unwrap_or(Response::with_capacity(
+            0,
+            "".to_string(),
+            "".to_string(),
+        )))
         .map_err(|e| Error::FirestoreSelectError(e))
 }
 
@@ -160,6 +208,21 @@ pub async fn shell_response_from_hash(
     ).await
 }
 
+pub async fn shell_audit_from_hash(
+    hash_cache_key: &str,
+) -> Result<Option<Audit>, Error> {
+    audit_from_hash(
+        hash_cache_key,
+        &get_db().await?,
+    ).await
+}
+
+pub async fn shell_record_snapshots(config: &Config) -> Result<usize, Error> {
+    let db = get_db(); // Unawaited Future
+    let client: Client = get_client().await?;
+    record_snapshots(&db.await?, client, config).await
+}
+
 pub async fn get_client() -> Result<Client, Error> {
     Client::connect(
         io::read_dst()?
@@ -168,6 +231,11 @@ pub async fn get_client() -> Result<Client, Error> {
         .map_err(|e| Error::GRPCConnectionError(e))
 }
 
+// Fails on the first parse or validation error in the file at BBBE_CONFIG
+pub fn get_config() -> Result<Config, Error> {
+    Config::load(&io::read_config_path()?)
+}
+
 pub async fn get_db() -> Result<FirestoreDb, Error> {
     FirestoreDb::with_options_token_source(
         FirestoreDbOptions::new(io::read_project_id().unwrap()),
@@ -178,15 +246,66 @@ pub async fn get_db() -> Result<FirestoreDb, Error> {
         .map_err(|e| Error::FirestoreConnectionError(e))
 }
 
-async fn get_price(
-    item: Item,
-    pricing_model: PricingModel,
+// Sends each distinct request of the whole appraisal once, then prices every
+// item from the shared replies. Items with a failed request are unavailable.
+// Also returns the orders behind each accepted item when auditing.
+async fn get_prices(
+    items: Vec<(Item, PricingModel)>,
     client: Client,
-) -> Result<(Item, Price, PriceSource), Error> {
-    pricing_model
-        .get_price(client)
-        .await
-        .map(|p| (item, p, pricing_model.price_source()))
+    db: &FirestoreDb,
+    cache: Option<&Cache>,
+    config: &Config,
+) -> (Vec<Priced>, Option<Audit>) {
+    let reqs: Vec<Req> = plan(items
+        .iter()
+        .flat_map(|(_, pricing_model)| pricing_model.to_reqs(config))
+    );
+    let reps: Reps = get_reps(
+        client,
+        db,
+        cache,
+        Some(Instant::now() + limits().deadline),
+        reqs,
+    ).await;
+    let priced: Vec<Priced> = items
+        .into_iter()
+        .map(|(item, pricing_model)| {
+            let priced = match pricing_model.error(config, &reps, item.quantity) {
+                Some(reason) => Err(reason.clone()),
+                None => Ok(pricing_model.get_price(config, &reps, item.quantity)),
+            };
+            (item, pricing_model, priced)
+        })
+        .collect();
+    let audit: Option<Audit> = match audit::enabled() {
+        true => Some(Audit::new(config, &priced, &reps)),
+        false => None,
+    };
+    (priced, audit)
+}
+
+// Pushes every priced item once the appraisal value they add up to is known
+fn push_priced(response: &mut Response, priced: Vec<Priced>, config: &Config) {
+    let value: f64 = priced
+        .iter()
+        .map(|(item, _, priced)| match priced {
+            Ok((Price::Accepted(f), _)) => f * item.quantity,
+            _ => 0.0,
+        })
+        .sum();
+    for (item, model, priced) in priced {
+        match priced {
+            Ok((price, price_source)) => {
+                let (price, price_source) = model.with_appraisal_value(
+                    price,
+                    price_source,
+                    value,
+                );
+                response.push(config, item, price, price_source);
+            },
+            Err(reason) => response.push_unavailable(item, reason),
+        }
+    }
 }
 
 // Returns false if the error is "AlreadyExists"
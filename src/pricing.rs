@@ -1,11 +1,20 @@
 use crate::{
-    {TypeId, Market, Quantity, Client, PriceMod, PriceSource, Location},
-    static_map::{PM_MAP, MAX_MULTI_ITEM, MAX_SUB_ITEM},
-    error::Error,
+    {TypeId, Market, Quantity, PriceMod, Location, SystemId, ItemName},
+    config::Config,
+    source::{PriceSource, ModelKind},
+    rpc::{Req, Reps},
+    history::SnapshotsReq,
     proto::*,
 };
 
-use futures::stream::{TryStreamExt, futures_unordered::FuturesUnordered};
+use std::ops::RangeInclusive;
+
+use serde::{Serialize, Deserialize};
+
+// How many levels of sub items a composite model may nest
+const MAX_SUB_DEPTH: usize = 8;
+// Modifiers outside this range are more likely typos than intended prices
+const SANE_PRICE_MOD: RangeInclusive<PriceMod> = 0.01..=1.5;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Price {
@@ -13,322 +22,1896 @@ pub enum Price {
     Rejected,
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum PricingModel {
-    SingleMarketSingleItemMinSell(SingleMarketSingleItemMinSell),
-    SingleMarketSingleItemMaxBuy(SingleMarketSingleItemMaxBuy),
-    SingleMarketMultiItemMaxBuy(SingleMarketMultiItemMaxBuy),
-    SubSingleItemsMaxBuy(SubSingleItemsMaxBuy),
-    Rejected,
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PricingModel {
+    SingleMarketSingleItemMinSell(SingleMarketSingleItemMinSell),
+    SingleMarketSingleItemMaxBuy(SingleMarketSingleItemMaxBuy),
+    SingleMarketMultiItemMaxBuy(SingleMarketMultiItemMaxBuy),
+    SubSingleItemsMaxBuy(SubSingleItemsMaxBuy),
+    SingleMarketSingleItemDepth(SingleMarketSingleItemDepth),
+    SingleMarketSingleItemPercentile(SingleMarketSingleItemPercentile),
+    AdjustedPriceModel(AdjustedPriceModel),
+    ManufacturingCost(ManufacturingCost),
+    MultiMarket(MultiMarket),
+    Reprocessing(Reprocessing),
+    Tiered(Tiered),
+    Guarded(Guarded),
+    Smoothed(Smoothed),
+    Fallback(Vec<PricingModel>), // The first model to accept wins
+    Rejected,
+}
+
+// Which system cost index an industry job is charged
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Activity {
+    Manufacturing,
+    Reactions,
+    Invention,
+}
+
+// How MultiMarket combines the prices of the markets that have orders
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Combine {
+    Max,
+    Min,
+    WeightedAverage,
+}
+
+// Reprocessing yield at a location
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReprocessYield {
+    pub structure: f64, // base yield including rigs and security
+    pub skills: f64, // bonus
+    pub implant: f64, // bonus
+}
+
+// What Tiered looks up its tiers by
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TierBasis {
+    Quantity, // each tier applies to the units inside it
+    ItemValue, // each tier applies to the ISK value inside it
+    AppraisalValue, // the tier containing the whole appraisal applies
+}
+
+// Thresholds past which Guarded rejects an item's book as manipulable
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Guards {
+    pub max_spread: Option<f64>, // (min sell - max buy) / max buy
+    pub min_top_volume: Option<f64>, // ISK at the best price
+    pub min_orders: Option<usize>,
+}
+
+// How Smoothed reduces the snapshot prices to one price
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Smoothing {
+    Mean,
+    Median,
+}
+
+// What to do with the quantity that the order book could not fill
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Unfilled {
+    Reject,
+    WorstPrice, // Price the remainder at the last order level reached
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SingleMarketSingleItemMaxBuy {
+    pub type_id: TypeId,
+    pub market: Market,
+    pub modifier: PriceMod,
+    pub description: String,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SingleMarketSingleItemMinSell {
+    pub type_id: TypeId,
+    pub market: Market,
+    pub modifier: PriceMod,
+    pub description: String,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SingleMarketMultiItemMaxBuy {
+    pub items: Vec<(TypeId, Quantity)>,
+    pub market: Market,
+    pub modifier: PriceMod,
+    pub description: String,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SubSingleItemsMaxBuy {
+    pub items: Vec<(ItemName, Quantity)>,
+    pub location: Location,
+    pub description: String,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SingleMarketSingleItemDepth {
+    pub type_id: TypeId,
+    pub market: Market,
+    pub buy: bool,
+    pub unfilled: Unfilled,
+    pub modifier: PriceMod,
+    pub description: String,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SingleMarketSingleItemPercentile {
+    pub type_id: TypeId,
+    pub market: Market,
+    pub buy: bool,
+    pub percentile: f64, // fraction of volume, from the best price, that must clear
+    pub max_std_devs: Option<f64>, // drop orders further than this from the mean
+    pub modifier: PriceMod,
+    pub description: String,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdjustedPriceModel {
+    pub type_id: TypeId,
+    pub modifier: PriceMod,
+    pub description: String,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManufacturingCost {
+    pub materials: Vec<(TypeId, Quantity)>, // input materials per unit produced
+    pub market: Market,
+    pub buy: bool, // for pricing the input materials
+    pub system_id: SystemId,
+    pub activity: Activity,
+    pub job_cost_bonus: f64, // of the structure, 0.0 - 1.0
+    pub facility_tax: f64, // 0.0 - 1.0
+    pub modifier: PriceMod,
+    pub description: String,
+}
+// Reprocessed at the yield config has for yield_location, or for the
+// location the outputs are priced at if it is not set
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reprocessing {
+    pub outputs: Vec<(ItemName, Quantity)>, // base outputs per portion
+    pub portion_size: Quantity,
+    pub location: Location, // the outputs are priced at
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yield_location: Option<Location>, // the ore is reprocessed at
+    pub description: String,
+}
+// AppraisalValue tiers only apply when Tiered is an item's top level model
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tiered {
+    pub model: Box<PricingModel>, // priced with a PriceMod of 1.0
+    pub basis: TierBasis,
+    pub tiers: Vec<(f64, PriceMod)>, // (lower bound, modifier), ascending
+    pub description: String,
+}
+// Wrap in a Fallback to fall back to a safer price instead of rejecting
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Guarded {
+    pub model: Box<PricingModel>,
+    pub type_id: TypeId,
+    pub market: Market,
+    pub buy: bool, // the side of the book that volume and orders are checked on
+    pub guards: Guards,
+    pub description: String,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Smoothed {
+    pub type_id: TypeId,
+    pub market: Market,
+    pub buy: bool,
+    pub days: u32, // of snapshots
+    pub smoothing: Smoothing,
+    pub cap: Option<f64>, // as a multiple of the live price
+    pub modifier: PriceMod,
+    pub description: String,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MultiMarket {
+    pub type_id: TypeId,
+    pub markets: Vec<(Market, f64)>, // (market, weight)
+    pub buy: bool,
+    pub combine: Combine,
+    pub modifier: PriceMod,
+    pub description: String,
+}
+
+trait WeveMarketMessages {
+    fn to_reqs(&self, config: &Config) -> Vec<Req>;
+    fn get_price(&self, config: &Config, reps: &Reps, quantity: Quantity) -> Price;
+    fn price_source(&self, config: &Config) -> PriceSource;
+    // Describes how get_price arrived at its price given these reps
+    fn price_source_for(
+        &self,
+        config: &Config,
+        _reps: &Reps,
+        _quantity: Quantity,
+    ) -> PriceSource {
+        self.price_source(config)
+    }
+    // The orders get_price priced from given these reps, for auditing
+    fn selected(
+        &self,
+        _config: &Config,
+        _reps: &Reps,
+        _quantity: Quantity,
+    ) -> Vec<Selected> {
+        vec![]
+    }
+}
+
+// An order a model priced from, with the book it was taken from
+pub type Selected = (MarketOrdersReq, MarketOrder);
+
+impl PricingModel {
+    // Prices from reps, which must hold the replies to self.to_reqs(config)
+    pub fn get_price(
+        &self,
+        config: &Config,
+        reps: &Reps,
+        quantity: Quantity,
+    ) -> (Price, PriceSource) {
+        (
+            self.get_price_inner(config, reps, quantity),
+            self.price_source_inner(config, reps, quantity),
+        )
+    }
+
+    // Applies AppraisalValue tiers once the whole appraisal is priced
+    pub fn with_appraisal_value(
+        &self,
+        price: Price,
+        price_source: PriceSource,
+        value: f64,
+    ) -> (Price, PriceSource) {
+        match (self, price) {
+            (PricingModel::Tiered(p), Price::Accepted(f))
+                if p.basis == TierBasis::AppraisalValue =>
+            {
+                let (lower, modifier): (f64, PriceMod) = p
+                    .containing_tier(value)
+                    .copied()
+                    .unwrap_or((0.0, 1.0));
+                (
+                    Price::Accepted(f * modifier),
+                    price_source.with_note(format!(
+                        "AppraisalValue {:.2} priced at {}+ x{}",
+                        value,
+                        lower,
+                        modifier,
+                    )),
+                )
+            },
+            _ => (price, price_source),
+        }
+    }
+
+    pub fn price_source(&self, config: &Config) -> PriceSource {
+        match self {
+            PricingModel::SingleMarketSingleItemMinSell(p) => p.price_source(config),
+            PricingModel::SingleMarketSingleItemMaxBuy(p) => p.price_source(config),
+            PricingModel::SingleMarketMultiItemMaxBuy(p) => p.price_source(config),
+            PricingModel::SubSingleItemsMaxBuy(p) => p.price_source(config),
+            PricingModel::SingleMarketSingleItemDepth(p) => p.price_source(config),
+            PricingModel::SingleMarketSingleItemPercentile(p) => p.price_source(config),
+            PricingModel::AdjustedPriceModel(p) => p.price_source(config),
+            PricingModel::ManufacturingCost(p) => p.price_source(config),
+            PricingModel::MultiMarket(p) => p.price_source(config),
+            PricingModel::Reprocessing(p) => p.price_source(config),
+            PricingModel::Tiered(p) => p.price_source(config),
+            PricingModel::Guarded(p) => p.price_source(config),
+            PricingModel::Smoothed(p) => p.price_source(config),
+            PricingModel::Fallback(v) => v
+                .iter()
+                .fold(
+                    PriceSource::new(ModelKind::Fallback, "Fallback"),
+                    |ps, p| ps.with_model(p.price_source(config)),
+                ),
+            PricingModel::Rejected => PriceSource::rejected(),
+        }
+    }
+
+    pub fn selected(
+        &self,
+        config: &Config,
+        reps: &Reps,
+        quantity: Quantity,
+    ) -> Vec<Selected> {
+        match self {
+            PricingModel::SingleMarketSingleItemMinSell(p) => p.selected(config, reps, quantity),
+            PricingModel::SingleMarketSingleItemMaxBuy(p) => p.selected(config, reps, quantity),
+            PricingModel::SingleMarketMultiItemMaxBuy(p) => p.selected(config, reps, quantity),
+            PricingModel::SubSingleItemsMaxBuy(p) => p.selected(config, reps, quantity),
+            PricingModel::SingleMarketSingleItemDepth(p) => p.selected(config, reps, quantity),
+            PricingModel::SingleMarketSingleItemPercentile(p) => p.selected(config, reps, quantity),
+            PricingModel::AdjustedPriceModel(p) => p.selected(config, reps, quantity),
+            PricingModel::ManufacturingCost(p) => p.selected(config, reps, quantity),
+            PricingModel::MultiMarket(p) => p.selected(config, reps, quantity),
+            PricingModel::Reprocessing(p) => p.selected(config, reps, quantity),
+            PricingModel::Tiered(p) => p.selected(config, reps, quantity),
+            PricingModel::Guarded(p) => p.selected(config, reps, quantity),
+            PricingModel::Smoothed(p) => p.selected(config, reps, quantity),
+            PricingModel::Fallback(v) => match v
+                .iter()
+                .find(|p| matches!(
+                    p.get_price_inner(config, reps, quantity),
+                    Price::Accepted(_),
+                ))
+            {
+                Some(p) => p.selected(config, reps, quantity),
+                None => vec![],
+            },
+            PricingModel::Rejected => vec![],
+        }
+    }
+
+    pub fn to_reqs(&self, config: &Config) -> Vec<Req> {
+        match self {
+            PricingModel::SingleMarketSingleItemMinSell(p) => p.to_reqs(config),
+            PricingModel::SingleMarketSingleItemMaxBuy(p) => p.to_reqs(config),
+            PricingModel::SingleMarketMultiItemMaxBuy(p) => p.to_reqs(config),
+            PricingModel::SubSingleItemsMaxBuy(p) => p.to_reqs(config),
+            PricingModel::SingleMarketSingleItemDepth(p) => p.to_reqs(config),
+            PricingModel::SingleMarketSingleItemPercentile(p) => p.to_reqs(config),
+            PricingModel::AdjustedPriceModel(p) => p.to_reqs(config),
+            PricingModel::ManufacturingCost(p) => p.to_reqs(config),
+            PricingModel::MultiMarket(p) => p.to_reqs(config),
+            PricingModel::Reprocessing(p) => p.to_reqs(config),
+            PricingModel::Tiered(p) => p.to_reqs(config),
+            PricingModel::Guarded(p) => p.to_reqs(config),
+            PricingModel::Smoothed(p) => p.to_reqs(config),
+            PricingModel::Fallback(v) => v
+                .iter()
+                .flat_map(|p| p.to_reqs(config))
+                .collect(),
+            PricingModel::Rejected => vec![],
+        }
+    }
+
+    // The books to snapshot for self, every book it prices from live and
+    // every book its Smoothed models average the snapshots of, which they
+    // only request live when capped
+    pub fn snapshot_reqs(&self, config: &Config) -> Vec<MarketOrdersReq> {
+        self.to_reqs(config)
+            .into_iter()
+            .filter_map(|req| match req {
+                Req::MarketOrders(req) => Some(req),
+                Req::Snapshots(req) => Some(orders_req(req.type_id, &req.market, req.buy)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Returns why a request that pricing self needs failed. Fallback steps
+    // are walked in order and only up to the step that accepts, so a failed
+    // request of a later step does not make the item unavailable, and sub
+    // items are walked the way get_price walks them
+    pub fn error<'r>(
+        &self,
+        config: &Config,
+        reps: &'r Reps,
+        quantity: Quantity,
+    ) -> Option<&'r String> {
+        match self {
+            PricingModel::Tiered(p) => p.model.error(config, reps, quantity),
+            PricingModel::Guarded(p) => reps
+                .error(&p.guard_reqs())
+                .or_else(|| p.model.error(config, reps, quantity)),
+            PricingModel::SubSingleItemsMaxBuy(p) => match p.check(config) {
+                Ok(()) => p
+                    .sub_items(config)
+                    .find_map(|(pm, _, qnt)| pm.error(config, reps, quantity * qnt)),
+                Err(_) => None,
+            },
+            PricingModel::Reprocessing(p) => match p.check(config) {
+                Ok(()) if quantity >= p.portion_size => p
+                    .outputs(config, quantity)
+                    .filter(|(_, _, qnt)| *qnt > 0.0)
+                    .find_map(|(pm, _, qnt)| pm.error(config, reps, qnt)),
+                _ => None,
+            },
+            PricingModel::Fallback(v) => {
+                for p in v.iter() {
+                    if let Some(reason) = p.error(config, reps, quantity) {
+                        return Some(reason);
+                    }
+                    if let Price::Accepted(_) = p.get_price_inner(config, reps, quantity) {
+                        return None;
+                    }
+                }
+                None
+            },
+            _ => reps.error(&self.to_reqs(config)),
+        }
+    }
+
+    // Returns a description of the first sub item beneath self that config
+    // does not price, of the first cycle or depth overrun, or of the first
+    // parameter out of range, such as tiers that are not ascending
+    pub fn check(&self, config: &Config) -> Result<(), String> {
+        for location in self.reprocessed_at() {
+            if !config.reprocess_yields.contains_key(location) {
+                return Err(no_reprocess_yield(location));
+            }
+        }
+        for (location, items) in self.sub_item_lists() {
+            for (item, _) in items.iter() {
+                if config.get(location, item).is_none() {
+                    return Err(format!(
+                        "sub item {} is not priced at location {}",
+                        item,
+                        location,
+                    ));
+                }
+            }
+            check_sub_items(
+                config,
+                location,
+                items,
+                &mut Vec::with_capacity(MAX_SUB_DEPTH),
+            )?;
+        }
+        match self.param_problems().into_iter().next() {
+            Some(problem) => Err(problem),
+            None => Ok(()),
+        }
+    }
+
+    // Returns every problem check would reject self for, and every problem
+    // that would load but most likely misprices, such as a modifier of 9.0
+    // meant as 0.9
+    pub fn lint(&self, config: &Config) -> Vec<String> {
+        let mut problems: Vec<String> = self
+            .reprocessed_at()
+            .into_iter()
+            .filter(|location| !config.reprocess_yields.contains_key(*location))
+            .map(no_reprocess_yield)
+            .collect();
+        for (location, items) in self.sub_item_lists() {
+            for (item, _) in items.iter() {
+                match config.get(location, item) {
+                    None => problems.push(format!(
+                        "sub item {} is not priced at location {}",
+                        item,
+                        location,
+                    )),
+                    Some(PricingModel::Rejected) => problems.push(format!(
+                        "sub item {} is rejected at location {}",
+                        item,
+                        location,
+                    )),
+                    Some(pm) if pm.appraisal_tiered() => problems.push(format!(
+                        "sub item {} at location {} has AppraisalValue tiers, \
+                        which price it at x1.0 as a sub item",
+                        item,
+                        location,
+                    )),
+                    Some(_) => (),
+                }
+            }
+            if let Err(e) = check_sub_items(
+                config,
+                location,
+                items,
+                &mut Vec::with_capacity(MAX_SUB_DEPTH),
+            ) {
+                problems.push(e);
+            }
+        }
+        for modifier in self.price_mods() {
+            if !SANE_PRICE_MOD.contains(&modifier) {
+                problems.push(format!(
+                    "modifier {} is outside {} - {}",
+                    modifier,
+                    SANE_PRICE_MOD.start(),
+                    SANE_PRICE_MOD.end(),
+                ));
+            }
+        }
+        problems.extend(self.param_problems());
+        problems
+    }
+
+    // The type_id of the single item self prices from the market, if any
+    pub fn type_id(&self) -> Option<TypeId> {
+        match self {
+            PricingModel::SingleMarketSingleItemMinSell(p) => Some(p.type_id),
+            PricingModel::SingleMarketSingleItemMaxBuy(p) => Some(p.type_id),
+            PricingModel::SingleMarketSingleItemDepth(p) => Some(p.type_id),
+            PricingModel::SingleMarketSingleItemPercentile(p) => Some(p.type_id),
+            PricingModel::AdjustedPriceModel(p) => Some(p.type_id),
+            PricingModel::MultiMarket(p) => Some(p.type_id),
+            PricingModel::Smoothed(p) => Some(p.type_id),
+            PricingModel::Guarded(p) => Some(p.type_id),
+            PricingModel::Tiered(p) => p.model.type_id(),
+            PricingModel::Fallback(v) => v
+                .iter()
+                .find_map(|p| p.type_id()),
+            _ => None,
+        }
+    }
+
+    // Every market self prices from, including those of the models it wraps
+    pub fn markets(&self) -> Vec<&str> {
+        match self {
+            PricingModel::SingleMarketSingleItemMinSell(p) => vec![p.market.as_str()],
+            PricingModel::SingleMarketSingleItemMaxBuy(p) => vec![p.market.as_str()],
+            PricingModel::SingleMarketMultiItemMaxBuy(p) => vec![p.market.as_str()],
+            PricingModel::SingleMarketSingleItemDepth(p) => vec![p.market.as_str()],
+            PricingModel::SingleMarketSingleItemPercentile(p) => vec![p.market.as_str()],
+            PricingModel::ManufacturingCost(p) => vec![p.market.as_str()],
+            PricingModel::Smoothed(p) => vec![p.market.as_str()],
+            PricingModel::MultiMarket(p) => p.markets
+                .iter()
+                .map(|(market, _)| market.as_str())
+                .collect(),
+            PricingModel::Tiered(p) => p.model.markets(),
+            PricingModel::Guarded(p) => {
+                let mut markets: Vec<&str> = vec![p.market.as_str()];
+                markets.extend(p.model.markets());
+                markets
+            },
+            PricingModel::Fallback(v) => v
+                .iter()
+                .flat_map(|p| p.markets())
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    // Every PriceMod in self, including those of the models it wraps
+    pub fn price_mods(&self) -> Vec<PriceMod> {
+        match self {
+            PricingModel::SingleMarketSingleItemMinSell(p) => vec![p.modifier],
+            PricingModel::SingleMarketSingleItemMaxBuy(p) => vec![p.modifier],
+            PricingModel::SingleMarketMultiItemMaxBuy(p) => vec![p.modifier],
+            PricingModel::SingleMarketSingleItemDepth(p) => vec![p.modifier],
+            PricingModel::SingleMarketSingleItemPercentile(p) => vec![p.modifier],
+            PricingModel::AdjustedPriceModel(p) => vec![p.modifier],
+            PricingModel::ManufacturingCost(p) => vec![p.modifier],
+            PricingModel::MultiMarket(p) => vec![p.modifier],
+            PricingModel::Smoothed(p) => vec![p.modifier],
+            PricingModel::Tiered(p) => p.tiers
+                .iter()
+                .map(|(_, modifier)| *modifier)
+                .chain(p.model.price_mods())
+                .collect(),
+            PricingModel::Guarded(p) => p.model.price_mods(),
+            PricingModel::Fallback(v) => v
+                .iter()
+                .flat_map(|p| p.price_mods())
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    // Describes the parameters of self, and of the models it wraps, that
+    // are out of range for what they measure
+    fn param_problems(&self) -> Vec<String> {
+        let fraction = |name: &str, f: f64| match (0.0..=1.0).contains(&f) {
+            true => None,
+            false => Some(format!("{} {} is outside 0.0 - 1.0", name, f)),
+        };
+        let nested = |p: &PricingModel| match p.appraisal_tiered() {
+            true => Some(
+                "AppraisalValue tiers beneath another model price at x1.0"
+                    .to_string()
+            ),
+            false => None,
+        };
+        match self {
+            PricingModel::SingleMarketSingleItemPercentile(p) => fraction(
+                "percentile",
+                p.percentile,
+            )
+                .into_iter()
+                .collect(),
+            PricingModel::ManufacturingCost(p) => [
+                fraction("structure job cost bonus", p.job_cost_bonus),
+                fraction("facility tax", p.facility_tax),
+            ]
+                .into_iter()
+                .flatten()
+                .collect(),
+            PricingModel::Tiered(p) => {
+                let mut problems: Vec<String> = p.model.param_problems();
+                problems.extend(nested(&p.model));
+                if p.tiers.is_empty() {
+                    problems.push("no tiers".to_string());
+                }
+                if p.tiers.windows(2).any(|w| w[0].0 >= w[1].0) {
+                    problems.push("tiers are not ascending".to_string());
+                }
+                problems
+            },
+            PricingModel::Guarded(p) => {
+                let mut problems: Vec<String> = p.model.param_problems();
+                problems.extend(nested(&p.model));
+                problems
+            },
+            PricingModel::Fallback(v) if v.is_empty() => vec![
+                "fallback has no steps".to_string(),
+            ],
+            PricingModel::Fallback(v) => v
+                .iter()
+                .flat_map(|p| p.param_problems().into_iter().chain(nested(p)))
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    // True if self applies AppraisalValue tiers, which with_appraisal_value
+    // only does for an item's top level model
+    fn appraisal_tiered(&self) -> bool {
+        matches!(
+            self,
+            PricingModel::Tiered(p) if p.basis == TierBasis::AppraisalValue,
+        )
+    }
+
+    // The sub item lists directly beneath self, looking through fallbacks
+    fn sub_item_lists(&self) -> Vec<(&str, &[(ItemName, Quantity)])> {
+        match self {
+            PricingModel::SubSingleItemsMaxBuy(p) => vec![(&p.location, &p.items[..])],
+            PricingModel::Reprocessing(p) => vec![(&p.location, &p.outputs[..])],
+            PricingModel::Tiered(p) => p.model.sub_item_lists(),
+            PricingModel::Guarded(p) => p.model.sub_item_lists(),
+            PricingModel::Fallback(v) => v
+                .iter()
+                .flat_map(|p| p.sub_item_lists())
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    // The locations self reprocesses at, looking through wrapping models
+    pub fn reprocessed_at(&self) -> Vec<&str> {
+        match self {
+            PricingModel::Reprocessing(p) => vec![p.yield_location()],
+            PricingModel::Tiered(p) => p.model.reprocessed_at(),
+            PricingModel::Guarded(p) => p.model.reprocessed_at(),
+            PricingModel::Fallback(v) => v
+                .iter()
+                .flat_map(|p| p.reprocessed_at())
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    fn get_price_inner(&self, config: &Config, reps: &Reps, quantity: Quantity) -> Price {
+        match self {
+            PricingModel::SingleMarketSingleItemMinSell(p) => p.get_price(config, reps, quantity),
+            PricingModel::SingleMarketSingleItemMaxBuy(p) => p.get_price(config, reps, quantity),
+            PricingModel::SingleMarketMultiItemMaxBuy(p) => p.get_price(config, reps, quantity),
+            PricingModel::SubSingleItemsMaxBuy(p) => p.get_price(config, reps, quantity),
+            PricingModel::SingleMarketSingleItemDepth(p) => p.get_price(config, reps, quantity),
+            PricingModel::SingleMarketSingleItemPercentile(p) => p.get_price(config, reps, quantity),
+            PricingModel::AdjustedPriceModel(p) => p.get_price(config, reps, quantity),
+            PricingModel::ManufacturingCost(p) => p.get_price(config, reps, quantity),
+            PricingModel::MultiMarket(p) => p.get_price(config, reps, quantity),
+            PricingModel::Reprocessing(p) => p.get_price(config, reps, quantity),
+            PricingModel::Tiered(p) => p.get_price(config, reps, quantity),
+            PricingModel::Guarded(p) => p.get_price(config, reps, quantity),
+            PricingModel::Smoothed(p) => p.get_price(config, reps, quantity),
+            PricingModel::Fallback(v) => match v
+                .iter()
+                .map(|p| p.get_price_inner(config, reps, quantity))
+                .find(|price| matches!(price, Price::Accepted(_)))
+            {
+                Some(price) => price,
+                None => Price::Rejected,
+            },
+            PricingModel::Rejected => Price::Rejected,
+        }
+    }
+
+    fn price_source_inner(
+        &self,
+        config: &Config,
+        reps: &Reps,
+        quantity: Quantity,
+    ) -> PriceSource {
+        match self {
+            PricingModel::SingleMarketSingleItemMinSell(p) => p.price_source_for(config, reps, quantity),
+            PricingModel::SingleMarketSingleItemMaxBuy(p) => p.price_source_for(config, reps, quantity),
+            PricingModel::SingleMarketMultiItemMaxBuy(p) => p.price_source_for(config, reps, quantity),
+            PricingModel::SubSingleItemsMaxBuy(p) => p.price_source_for(config, reps, quantity),
+            PricingModel::SingleMarketSingleItemDepth(p) => p.price_source_for(config, reps, quantity),
+            PricingModel::SingleMarketSingleItemPercentile(p) => p.price_source_for(config, reps, quantity),
+            PricingModel::AdjustedPriceModel(p) => p.price_source_for(config, reps, quantity),
+            PricingModel::ManufacturingCost(p) => p.price_source_for(config, reps, quantity),
+            PricingModel::MultiMarket(p) => p.price_source_for(config, reps, quantity),
+            PricingModel::Reprocessing(p) => p.price_source_for(config, reps, quantity),
+            PricingModel::Tiered(p) => p.price_source_for(config, reps, quantity),
+            PricingModel::Guarded(p) => p.price_source_for(config, reps, quantity),
+            PricingModel::Smoothed(p) => p.price_source_for(config, reps, quantity),
+            PricingModel::Fallback(v) => match v
+                .iter()
+                .position(|p| matches!(
+                    p.get_price_inner(config, reps, quantity),
+                    Price::Accepted(_),
+                ))
+            {
+                Some(i) => PriceSource::new(ModelKind::Fallback, "Fallback")
+                    .with_note(format!("step {} of {} accepted", i + 1, v.len()))
+                    .with_model(v[i].price_source_inner(config, reps, quantity)),
+                None => self
+                    .price_source(config)
+                    .with_note("no step accepted".to_string()),
+            },
+            PricingModel::Rejected => self.price_source(config),
+        }
+    }
+}
+
+impl WeveMarketMessages for SingleMarketSingleItemMaxBuy {
+    fn to_reqs(&self, _config: &Config) -> Vec<Req> {
+        vec![Req::MarketOrders(orders_req(self.type_id, &self.market, true))]
+    }
+
+    fn get_price(&self, _config: &Config, reps: &Reps, _quantity: Quantity) -> Price {
+        match orders(reps, &orders_req(self.type_id, &self.market, true))
+            .iter()
+            .max_by(|o1, o2| order_f64(&o1.price, &o2.price))
+        {
+            Some(order) => Price::Accepted(order.price * self.modifier),
+            None => Price::Rejected, // This is when there are no orders
+        }
+    }
+
+    fn price_source(&self, _config: &Config) -> PriceSource {
+        PriceSource::new(ModelKind::SingleMarketSingleItemMaxBuy, &self.description)
+            .with_market(&self.market)
+            .with_modifier(self.modifier)
+    }
+
+    fn selected(
+        &self,
+        _config: &Config,
+        reps: &Reps,
+        _quantity: Quantity,
+    ) -> Vec<Selected> {
+        best(reps, orders_req(self.type_id, &self.market, true))
+            .into_iter()
+            .collect()
+    }
+}
+
+impl WeveMarketMessages for SingleMarketSingleItemMinSell {
+    fn to_reqs(&self, _config: &Config) -> Vec<Req> {
+        vec![Req::MarketOrders(orders_req(self.type_id, &self.market, false))]
+    }
+
+    fn get_price(&self, _config: &Config, reps: &Reps, _quantity: Quantity) -> Price {
+        match orders(reps, &orders_req(self.type_id, &self.market, false))
+            .iter()
+            .min_by(|o1, o2| rev_order_f64(&o1.price, &o2.price))
+        {
+            Some(order) => Price::Accepted(order.price * self.modifier),
+            None => Price::Rejected, // This is when there are no orders
+        }
+    }
+
+    fn price_source(&self, _config: &Config) -> PriceSource {
+        PriceSource::new(ModelKind::SingleMarketSingleItemMinSell, &self.description)
+            .with_market(&self.market)
+            .with_modifier(self.modifier)
+    }
+
+    fn selected(
+        &self,
+        _config: &Config,
+        reps: &Reps,
+        _quantity: Quantity,
+    ) -> Vec<Selected> {
+        best(reps, orders_req(self.type_id, &self.market, false))
+            .into_iter()
+            .collect()
+    }
+}
+
+impl WeveMarketMessages for SingleMarketMultiItemMaxBuy {
+    fn to_reqs(&self, _config: &Config) -> Vec<Req> {
+        self.items
+            .iter()
+            .map(|(type_id, _)| Req::MarketOrders(
+                orders_req(*type_id, &self.market, true)
+            ))
+            .collect()
+    }
+
+    fn get_price(&self, _config: &Config, reps: &Reps, _quantity: Quantity) -> Price {
+        let mut price: f64 = 0.0;
+        for (type_id, quantity) in self.items.iter() {
+            match orders(reps, &orders_req(*type_id, &self.market, true))
+                .iter()
+                .max_by(|o1, o2| order_f64(&o1.price, &o2.price))
+            {
+                Some(order) => price += order.price * quantity,
+                None => return Price::Rejected,
+            }
+        }
+        Price::Accepted(price * self.modifier)
+    }
+
+    fn price_source(&self, _config: &Config) -> PriceSource {
+        self.items
+            .iter()
+            .fold(
+                PriceSource::new(ModelKind::SingleMarketMultiItemMaxBuy, &self.description)
+                    .with_market(&self.market)
+                    .with_modifier(self.modifier),
+                |ps, (type_id, quantity)| ps.with_component(
+                    &type_id.to_string(),
+                    *quantity,
+                    None,
+                ),
+            )
+    }
+
+    fn selected(
+        &self,
+        _config: &Config,
+        reps: &Reps,
+        _quantity: Quantity,
+    ) -> Vec<Selected> {
+        self.items
+            .iter()
+            .filter_map(|(type_id, _)| best(reps, orders_req(*type_id, &self.market, true)))
+            .collect()
+    }
+}
+
+impl SubSingleItemsMaxBuy {
+    fn sub_items<'c>(&'c self, config: &'c Config) -> impl Iterator<
+        Item = (&'c PricingModel, &'c str, Quantity)
+    > + 'c {
+        sub_items(config, &self.location, &self.items)
+    }
+
+    // Returns a description of the first cycle or depth overrun beneath self
+    fn check(&self, config: &Config) -> Result<(), String> {
+        check_sub_items(
+            config,
+            &self.location,
+            &self.items,
+            &mut Vec::with_capacity(MAX_SUB_DEPTH),
+        )
+    }
+}
+
+impl WeveMarketMessages for SubSingleItemsMaxBuy {
+    fn to_reqs(&self, config: &Config) -> Vec<Req> {
+        if self.check(config).is_err() {
+            return vec![];
+        }
+        self.sub_items(config)
+            .flat_map(|(pm, _, _)| pm.to_reqs(config))
+            .collect()
+    }
+
+    fn get_price(&self, config: &Config, reps: &Reps, quantity: Quantity) -> Price {
+        if self.check(config).is_err() {
+            return Price::Rejected;
+        }
+        let mut priced: bool = false;
+        let mut price: f64 = 0.0;
+        for (pm, _, qnt) in self.sub_items(config) {
+            match pm.get_price_inner(config, reps, quantity * qnt) {
+                Price::Accepted(siprice) => {
+                    priced = true;
+                    price += siprice * qnt;
+                },
+                Price::Rejected => (),
+            }
+        }
+        match priced {
+            true => Price::Accepted(price),
+            false => Price::Rejected,
+        }
+    }
+
+    fn price_source(&self, config: &Config) -> PriceSource {
+        let ps = PriceSource::new(ModelKind::SubSingleItemsMaxBuy, &self.description);
+        if let Err(e) = self.check(config) {
+            return ps.with_note(e);
+        }
+        self.sub_items(config)
+            .fold(ps, |ps, (pm, item, qnt)| ps.with_component(
+                item,
+                qnt,
+                Some(pm.price_source(config)),
+            ))
+    }
+
+    fn selected(
+        &self,
+        config: &Config,
+        reps: &Reps,
+        quantity: Quantity,
+    ) -> Vec<Selected> {
+        if self.check(config).is_err() {
+            return vec![];
+        }
+        self.sub_items(config)
+            .flat_map(|(pm, _, qnt)| pm.selected(config, reps, quantity * qnt))
+            .collect()
+    }
+}
+
+impl SingleMarketSingleItemDepth {
+    // Returns the book sorted from the best price outwards
+    fn book(&self, reps: &Reps) -> Vec<MarketOrder> {
+        let mut orders: Vec<MarketOrder> = orders(
+            reps,
+            &orders_req(self.type_id, &self.market, self.buy),
+        ).to_vec();
+        match self.buy {
+            true => orders.sort_by(|o1, o2| order_f64(&o2.price, &o1.price)),
+            false => orders.sort_by(|o1, o2| rev_order_f64(&o1.price, &o2.price)),
+        };
+        orders
+    }
+}
+
+impl WeveMarketMessages for SingleMarketSingleItemDepth {
+    fn to_reqs(&self, _config: &Config) -> Vec<Req> {
+        vec![Req::MarketOrders(orders_req(self.type_id, &self.market, self.buy))]
+    }
+
+    // Walks the book from the best price outwards until quantity is filled,
+    // and returns the volume-weighted average price of the fill
+    fn get_price(&self, _config: &Config, reps: &Reps, quantity: Quantity) -> Price {
+        let orders: Vec<MarketOrder> = self.book(reps);
+
+        if quantity <= 0.0 { // Nothing to fill, so use the top of the book
+            return match orders.first() {
+                Some(order) => Price::Accepted(order.price * self.modifier),
+                None => Price::Rejected,
+            };
+        }
+
+        let mut remaining: Quantity = quantity;
+        let mut total: f64 = 0.0;
+        let mut worst: Option<f64> = None;
+        for order in orders.iter() {
+            if remaining <= 0.0 {
+                break;
+            }
+            let fill: Quantity = remaining.min(order.quantity as Quantity);
+            total += fill * order.price;
+            remaining -= fill;
+            worst = Some(order.price);
+        }
+
+        match (worst, self.unfilled) {
+            (None, _) => Price::Rejected, // This is when there are no orders
+            (Some(_), Unfilled::Reject) if remaining > 0.0 => Price::Rejected,
+            (Some(worst), _) => {
+                if remaining > 0.0 {
+                    total += remaining * worst;
+                }
+                Price::Accepted(total / quantity * self.modifier)
+            },
+        }
+    }
+
+    fn price_source(&self, _config: &Config) -> PriceSource {
+        PriceSource::new(ModelKind::SingleMarketSingleItemDepth, &self.description)
+            .with_market(&self.market)
+            .with_modifier(self.modifier)
+            .with_note(format!(
+                "{} side, unfilled quantity {:?}",
+                side(self.buy),
+                self.unfilled,
+            ))
+    }
+
+    // The orders the fill reached
+    fn selected(
+        &self,
+        _config: &Config,
+        reps: &Reps,
+        quantity: Quantity,
+    ) -> Vec<Selected> {
+        let mut remaining: Quantity = quantity;
+        let mut selected: Vec<Selected> = Vec::new();
+        for order in self.book(reps) {
+            remaining -= order.quantity as Quantity;
+            selected.push((orders_req(self.type_id, &self.market, self.buy), order));
+            if remaining <= 0.0 {
+                break;
+            }
+        }
+        selected
+    }
+}
+
+impl SingleMarketSingleItemPercentile {
+    // Returns the order at which the percentile of the book's volume clears,
+    // counting from the best price, so that a single small order cannot set
+    // the price
+    fn clearing(&self, reps: &Reps) -> Option<MarketOrder> {
+        let mut orders: Vec<MarketOrder> = orders(
+            reps,
+            &orders_req(self.type_id, &self.market, self.buy),
+        ).to_vec();
+
+        if let Some(max_dev) = self.max_std_devs {
+            orders = drop_outliers(orders, max_dev);
+        }
+        match self.buy {
+            true => orders.sort_by(|o1, o2| order_f64(&o2.price, &o1.price)),
+            false => orders.sort_by(|o1, o2| rev_order_f64(&o1.price, &o2.price)),
+        };
+
+        let volume: f64 = orders
+            .iter()
+            .map(|o| o.quantity as f64)
+            .sum();
+        let target: f64 = volume * self.percentile.clamp(0.0, 1.0);
+        let mut cleared: f64 = 0.0;
+        for order in orders.iter() {
+            cleared += order.quantity as f64;
+            if cleared >= target {
+                return Some(order.clone());
+            }
+        }
+        orders.pop()
+    }
+}
+
+impl WeveMarketMessages for SingleMarketSingleItemPercentile {
+    fn to_reqs(&self, _config: &Config) -> Vec<Req> {
+        vec![Req::MarketOrders(orders_req(self.type_id, &self.market, self.buy))]
+    }
+
+    fn get_price(&self, _config: &Config, reps: &Reps, _quantity: Quantity) -> Price {
+        match self.clearing(reps) {
+            Some(order) => Price::Accepted(order.price * self.modifier),
+            None => Price::Rejected, // This is when there are no orders
+        }
+    }
+
+    fn price_source(&self, _config: &Config) -> PriceSource {
+        let ps = PriceSource::new(ModelKind::SingleMarketSingleItemPercentile, &self.description)
+            .with_market(&self.market)
+            .with_modifier(self.modifier)
+            .with_note(format!(
+                "{}th percentile of {} volume",
+                self.percentile * 100.0,
+                side(self.buy),
+            ));
+        match self.max_std_devs {
+            Some(max_dev) => ps.with_note(format!(
+                "orders beyond {} std devs dropped",
+                max_dev,
+            )),
+            None => ps,
+        }
+    }
+
+    fn selected(
+        &self,
+        _config: &Config,
+        reps: &Reps,
+        _quantity: Quantity,
+    ) -> Vec<Selected> {
+        self.clearing(reps)
+            .map(|order| (orders_req(self.type_id, &self.market, self.buy), order))
+            .into_iter()
+            .collect()
+    }
+}
+
+impl WeveMarketMessages for AdjustedPriceModel {
+    fn to_reqs(&self, _config: &Config) -> Vec<Req> {
+        vec![Req::AdjustedPrice(AdjustedPriceReq { type_id: self.type_id })]
+    }
+
+    fn get_price(&self, _config: &Config, reps: &Reps, _quantity: Quantity) -> Price {
+        match reps.adjusted_price(&AdjustedPriceReq { type_id: self.type_id }) {
+            Some(rep) if rep.adjusted_price > 0.0 => Price::Accepted(
+                rep.adjusted_price * self.modifier
+            ),
+            _ => Price::Rejected, // CCP has no adjusted price for this item
+        }
+    }
+
+    fn price_source(&self, _config: &Config) -> PriceSource {
+        PriceSource::new(ModelKind::AdjustedPrice, &self.description)
+            .with_modifier(self.modifier)
+    }
+}
+
+impl ManufacturingCost {
+    // Returns (material cost, job install cost) per unit produced, or None
+    // if any input material has no orders
+    fn costs(&self, reps: &Reps) -> Option<(f64, f64)> {
+        let mut materials: f64 = 0.0;
+        let mut item_value: f64 = 0.0;
+        for (type_id, quantity) in self.materials.iter() {
+            let book = orders(reps, &orders_req(*type_id, &self.market, self.buy));
+            let order = match self.buy {
+                true => book
+                    .iter()
+                    .max_by(|o1, o2| order_f64(&o1.price, &o2.price)),
+                false => book
+                    .iter()
+                    .min_by(|o1, o2| rev_order_f64(&o1.price, &o2.price)),
+            }?;
+            materials += order.price * quantity;
+            item_value += reps
+                .adjusted_price(&AdjustedPriceReq { type_id: *type_id })
+                .map(|rep| rep.adjusted_price)
+                .unwrap_or(0.0) * quantity;
+        }
+        let index: f64 = reps
+            .system_index(&SystemIndexReq { system_id: self.system_id })
+            .map(|rep| match self.activity {
+                Activity::Manufacturing => rep.manufacturing,
+                Activity::Reactions => rep.reactions,
+                Activity::Invention => rep.invention,
+            })
+            .unwrap_or(0.0);
+        Some((
+            materials,
+            item_value * (index * (1.0 - self.job_cost_bonus) + self.facility_tax),
+        ))
+    }
+}
+
+impl WeveMarketMessages for ManufacturingCost {
+    fn to_reqs(&self, _config: &Config) -> Vec<Req> {
+        let mut reqs: Vec<Req> = Vec::with_capacity(self.materials.len() * 2 + 1);
+        for (type_id, _) in self.materials.iter() {
+            reqs.push(Req::MarketOrders(orders_req(*type_id, &self.market, self.buy)));
+            reqs.push(Req::AdjustedPrice(AdjustedPriceReq { type_id: *type_id }));
+        }
+        reqs.push(Req::SystemIndex(SystemIndexReq { system_id: self.system_id }));
+        reqs
+    }
+
+    fn get_price(&self, _config: &Config, reps: &Reps, _quantity: Quantity) -> Price {
+        match self.costs(reps) {
+            Some((materials, job)) => Price::Accepted((materials + job) * self.modifier),
+            None => Price::Rejected, // An input material has no orders
+        }
+    }
+
+    fn price_source(&self, _config: &Config) -> PriceSource {
+        self.materials
+            .iter()
+            .fold(
+                PriceSource::new(ModelKind::ManufacturingCost, &self.description)
+                    .with_market(&self.market)
+                    .with_modifier(self.modifier),
+                |ps, (type_id, quantity)| ps.with_component(
+                    &type_id.to_string(),
+                    *quantity,
+                    None,
+                ),
+            )
+    }
+
+    fn price_source_for(
+        &self,
+        config: &Config,
+        reps: &Reps,
+        _quantity: Quantity,
+    ) -> PriceSource {
+        match self.costs(reps) {
+            Some((materials, job)) => self
+                .price_source(config)
+                .with_note(format!(
+                    "materials {:.2} + {:?} job in system {} {:.2}",
+                    materials,
+                    self.activity,
+                    self.system_id,
+                    job,
+                )),
+            None => self
+                .price_source(config)
+                .with_note("input material has no orders".to_string()),
+        }
+    }
+
+    fn selected(
+        &self,
+        _config: &Config,
+        reps: &Reps,
+        _quantity: Quantity,
+    ) -> Vec<Selected> {
+        self.materials
+            .iter()
+            .filter_map(|(type_id, _)| best(reps, orders_req(*type_id, &self.market, self.buy)))
+            .collect()
+    }
+}
+
+impl MultiMarket {
+    // Returns the top of the book for each market that has orders
+    fn market_prices(&self, reps: &Reps) -> Vec<(&str, f64, f64)> {
+        self.markets
+            .iter()
+            .filter_map(|(market, weight)| {
+                let book = orders(reps, &orders_req(self.type_id, market, self.buy));
+                match self.buy {
+                    true => book
+                        .iter()
+                        .max_by(|o1, o2| order_f64(&o1.price, &o2.price)),
+                    false => book
+                        .iter()
+                        .min_by(|o1, o2| rev_order_f64(&o1.price, &o2.price)),
+                }
+                    .map(|order| (market.as_str(), order.price, *weight))
+            })
+            .collect()
+    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct SingleMarketSingleItemMaxBuy(
-    pub TypeId,
-    pub Market,
-    pub PriceMod,
-    pub &'static str,
-);
-#[derive(Debug, Clone, PartialEq)]
-pub struct SingleMarketSingleItemMinSell(
-    pub TypeId,
-    pub Market,
-    pub PriceMod,
-    pub &'static str,
-);
-#[derive(Debug, Clone, PartialEq)]
-pub struct SingleMarketMultiItemMaxBuy(
-    pub [Option<(TypeId, Quantity)>; MAX_MULTI_ITEM],
-    pub Market,
-    pub PriceMod,
-    pub &'static str,
-);
-#[derive(Debug, Clone, PartialEq)]
-pub struct SubSingleItemsMaxBuy(
-    pub [(&'static str, Quantity); MAX_SUB_ITEM],
-    pub Location,
-    pub &'static str,
-);
+impl ReprocessYield {
+    pub fn total(&self) -> f64 {
+        self.structure * (1.0 + self.skills) * (1.0 + self.implant)
+    }
+}
 
-trait WeveMarketMessages {
-    fn to_reqs(&self) -> Vec<MarketOrdersReq>;
-    fn get_price(
-        &self,
-        reps: Vec<(MarketOrdersReq, MarketOrdersRep)>,
-    ) -> Price;
-    fn price_source(&self) -> PriceSource;
+impl Reprocessing {
+    // Returns each output with its quantity from reprocessing quantity units,
+    // floored per output the way the game does
+    fn outputs<'c>(&'c self, config: &'c Config, quantity: Quantity) -> impl Iterator<
+        Item = (&'c PricingModel, &'c str, Quantity)
+    > + 'c {
+        let portions: f64 = match self.portion_size > 0.0 {
+            true => (quantity / self.portion_size).floor(),
+            false => 0.0,
+        };
+        let yld: f64 = self.reprocess_yield(config);
+        sub_items(config, &self.location, &self.outputs)
+            .map(move |(pm, item, base)| (pm, item, (base * portions * yld).floor()))
+    }
+
+    fn yield_location(&self) -> &str {
+        self.yield_location
+            .as_deref()
+            .unwrap_or(&self.location)
+    }
+
+    fn reprocess_yield(&self, config: &Config) -> f64 {
+        config
+            .reprocess_yields
+            .get(self.yield_location())
+            .map(|y| y.total())
+            .unwrap_or(0.0)
+    }
+
+    // Returns a description of the missing yield, or of the first cycle or
+    // depth overrun beneath self
+    fn check(&self, config: &Config) -> Result<(), String> {
+        if !config.reprocess_yields.contains_key(self.yield_location()) {
+            return Err(no_reprocess_yield(self.yield_location()));
+        }
+        check_sub_items(
+            config,
+            &self.location,
+            &self.outputs,
+            &mut Vec::with_capacity(MAX_SUB_DEPTH),
+        )
+    }
 }
 
-impl PricingModel {
-    pub async fn get_price(&self, client: Client) -> Result<Price, Error> {
-        if let PricingModel::Rejected = self {
-            return Ok(Price::Rejected);
+impl WeveMarketMessages for Reprocessing {
+    fn to_reqs(&self, config: &Config) -> Vec<Req> {
+        if self.check(config).is_err() {
+            return vec![];
         }
-        let reps: Vec<(MarketOrdersReq, MarketOrdersRep)> = self
-            .to_reqs()
-            .into_iter()
-            .map(|req| get_market_orders(client.clone(), req))
-            .collect::<FuturesUnordered<_>>()
-            .try_collect() // Do not try to type-annotate this
-            .await?;
-        Ok(self.get_price_inner(reps))
+        sub_items(config, &self.location, &self.outputs)
+            .flat_map(|(pm, _, _)| pm.to_reqs(config))
+            .collect()
     }
 
-    pub fn price_source(&self) -> PriceSource {
-        match self {
-            PricingModel::SingleMarketSingleItemMinSell(p) => p.price_source(),
-            PricingModel::SingleMarketSingleItemMaxBuy(p) => p.price_source(),
-            PricingModel::SingleMarketMultiItemMaxBuy(p) => p.price_source(),
-            PricingModel::SubSingleItemsMaxBuy(p) => p.price_source(),
-            PricingModel::Rejected => "Rejected".to_string(),
+    fn get_price(&self, config: &Config, reps: &Reps, quantity: Quantity) -> Price {
+        if self.check(config).is_err() || quantity < self.portion_size {
+            return Price::Rejected; // Not even one portion to reprocess
+        }
+        let mut priced: bool = false;
+        let mut price: f64 = 0.0;
+        for (pm, _, qnt) in self.outputs(config, quantity) {
+            if qnt <= 0.0 {
+                continue;
+            }
+            match pm.get_price_inner(config, reps, qnt) {
+                Price::Accepted(siprice) => {
+                    priced = true;
+                    price += siprice * qnt;
+                },
+                Price::Rejected => (),
+            }
+        }
+        match priced {
+            true => Price::Accepted(price / quantity),
+            false => Price::Rejected,
         }
     }
 
-    fn to_reqs(&self) -> Vec<MarketOrdersReq> {
-        match self {
-            PricingModel::SingleMarketSingleItemMinSell(p) => p.to_reqs(),
-            PricingModel::SingleMarketSingleItemMaxBuy(p) => p.to_reqs(),
-            PricingModel::SingleMarketMultiItemMaxBuy(p) => p.to_reqs(),
-            PricingModel::SubSingleItemsMaxBuy(p) => p.to_reqs(),
-            PricingModel::Rejected => vec![],
+    fn price_source(&self, config: &Config) -> PriceSource {
+        let ps = PriceSource::new(ModelKind::Reprocessing, &self.description);
+        if let Err(e) = self.check(config) {
+            return ps.with_note(e);
         }
+        sub_items(config, &self.location, &self.outputs)
+            .fold(
+                ps.with_note(format!(
+                    "{:.2}% reprocessing yield at {}, portions of {}",
+                    self.reprocess_yield(config) * 100.0,
+                    self.yield_location(),
+                    self.portion_size,
+                )),
+                |ps, (pm, item, qnt)| ps.with_component(
+                    item,
+                    qnt,
+                    Some(pm.price_source(config)),
+                ),
+            )
     }
 
-    fn get_price_inner(
+    fn selected(
         &self,
-        reps: Vec<(MarketOrdersReq, MarketOrdersRep)>,
-    ) -> Price {
-        match self {
-            PricingModel::SingleMarketSingleItemMinSell(p) => p.get_price(reps),
-            PricingModel::SingleMarketSingleItemMaxBuy(p) => p.get_price(reps),
-            PricingModel::SingleMarketMultiItemMaxBuy(p) => p.get_price(reps),
-            PricingModel::SubSingleItemsMaxBuy(p) => p.get_price(reps),
-            PricingModel::Rejected => Price::Rejected,
+        config: &Config,
+        reps: &Reps,
+        quantity: Quantity,
+    ) -> Vec<Selected> {
+        if self.check(config).is_err() || quantity < self.portion_size {
+            return vec![];
         }
+        self.outputs(config, quantity)
+            .filter(|(_, _, qnt)| *qnt > 0.0)
+            .flat_map(|(pm, _, qnt)| pm.selected(config, reps, qnt))
+            .collect()
     }
 }
 
-impl WeveMarketMessages for SingleMarketSingleItemMaxBuy {
-    fn to_reqs(&self) -> Vec<MarketOrdersReq> {
-        vec![MarketOrdersReq {
-            type_id: self.0,
-            market: self.1.to_string(),
-            buy: true,
-        }]
+impl Tiered {
+    // Returns the average modifier over amount, with each tier's modifier
+    // applying to the part of amount inside that tier
+    fn marginal(&self, amount: f64) -> PriceMod {
+        if amount <= 0.0 {
+            return self.containing(amount);
+        }
+        self.applied(amount)
+            .iter()
+            .map(|(_, modifier, inside)| modifier * inside)
+            .sum::<f64>() / amount
     }
 
-    fn get_price(
-        &self,
-        reps: Vec<(MarketOrdersReq, MarketOrdersRep)>,
-    ) -> Price {
-        match reps
-            .into_iter()
-            .next()
-            .unwrap() // If this were None, we would have returned an error in async get_price
-            .1
-            .market_orders
-            .into_iter()
-            .max_by(|o1, o2| order_f64(&o1.price, &o2.price))
-        {
-            Some(order) => Price::Accepted(order.price * self.2),
-            None => Price::Rejected, // This is when there are no orders
+    // Returns every tier amount reaches into, as (lower bound, modifier, the
+    // part of amount inside the tier)
+    fn applied(&self, amount: f64) -> Vec<(f64, PriceMod, f64)> {
+        let mut applied: Vec<(f64, PriceMod, f64)> = Vec::new();
+        for (i, (lower, modifier)) in self.tiers.iter().enumerate() {
+            let bottom: f64 = match i {
+                0 => 0.0, // the first tier covers everything below it too
+                _ => *lower,
+            };
+            let upper: f64 = self.tiers
+                .get(i + 1)
+                .map(|(upper, _)| *upper)
+                .unwrap_or(f64::INFINITY);
+            if amount > bottom {
+                applied.push((*lower, *modifier, amount.min(upper) - bottom));
+            }
         }
+        applied
+    }
+
+    // Returns the modifier of the tier containing amount
+    fn containing(&self, amount: f64) -> PriceMod {
+        self.containing_tier(amount)
+            .map(|(_, modifier)| *modifier)
+            .unwrap_or(1.0)
+    }
+
+    fn containing_tier(&self, amount: f64) -> Option<&(f64, PriceMod)> {
+        self.tiers
+            .iter()
+            .rev()
+            .find(|(lower, _)| amount >= *lower)
+            .or(self.tiers.first())
+    }
+
+    // Describes the tiers an amount of basis was priced at
+    fn applied_note(&self, amount: f64) -> String {
+        format!(
+            "{:?} {:.2} priced at {}",
+            self.basis,
+            amount,
+            self.applied(amount)
+                .iter()
+                .map(|(lower, modifier, inside)| format!(
+                    "{}+ x{} on {:.2}",
+                    lower,
+                    modifier,
+                    inside,
+                ))
+                .collect::<Vec<String>>()
+                .join(", "),
+        )
     }
 
-    fn price_source(&self) -> PriceSource {
-        self.3.to_string()
+    fn tiers(&self) -> String {
+        format!(
+            "{:?} tiers {}",
+            self.basis,
+            self.tiers
+                .iter()
+                .map(|(lower, modifier)| format!("{}+ x{}", lower, modifier))
+                .collect::<Vec<String>>()
+                .join(", "),
+        )
     }
 }
 
-impl WeveMarketMessages for SingleMarketSingleItemMinSell {
-    fn to_reqs(&self) -> Vec<MarketOrdersReq> {
-        vec![MarketOrdersReq {
-            type_id: self.0,
-            market: self.1.to_string(),
-            buy: false,
-        }]
+impl WeveMarketMessages for Tiered {
+    fn to_reqs(&self, config: &Config) -> Vec<Req> {
+        self.model.to_reqs(config)
     }
 
-    fn get_price(
-        &self,
-        reps: Vec<(MarketOrdersReq, MarketOrdersRep)>,
-    ) -> Price {
-        match reps
-            .into_iter()
-            .next()
-            .unwrap() // If this were None, we would have returned an error in async get_price
-            .1
-            .market_orders
-            .into_iter()
-            .min_by(|o1, o2| rev_order_f64(&o1.price, &o2.price))
-        {
-            Some(order) => Price::Accepted(order.price * self.2),
-            None => Price::Rejected, // This is when there are no orders
+    fn get_price(&self, config: &Config, reps: &Reps, quantity: Quantity) -> Price {
+        match self.model.get_price_inner(config, reps, quantity) {
+            Price::Accepted(price) => Price::Accepted(price * match self.basis {
+                TierBasis::Quantity => self.marginal(quantity),
+                TierBasis::ItemValue => self.marginal(price * quantity),
+                TierBasis::AppraisalValue => 1.0, // see with_appraisal_value
+            }),
+            Price::Rejected => Price::Rejected,
         }
     }
 
-    fn price_source(&self) -> PriceSource {
-        self.3.to_string()
+    fn price_source(&self, config: &Config) -> PriceSource {
+        PriceSource::new(ModelKind::Tiered, &self.description)
+            .with_note(self.tiers())
+            .with_model(self.model.price_source(config))
+    }
+
+    // Notes the tiers applied, except AppraisalValue tiers which are only
+    // known once the appraisal is priced, see with_appraisal_value
+    fn price_source_for(
+        &self,
+        config: &Config,
+        reps: &Reps,
+        quantity: Quantity,
+    ) -> PriceSource {
+        let ps = PriceSource::new(ModelKind::Tiered, &self.description)
+            .with_note(self.tiers());
+        let ps = match (self.basis, self.model.get_price_inner(config, reps, quantity)) {
+            (TierBasis::Quantity, Price::Accepted(_)) => ps
+                .with_note(self.applied_note(quantity)),
+            (TierBasis::ItemValue, Price::Accepted(price)) => ps
+                .with_note(self.applied_note(price * quantity)),
+            _ => ps,
+        };
+        ps.with_model(self.model.price_source_inner(config, reps, quantity))
+    }
+
+    fn selected(
+        &self,
+        config: &Config,
+        reps: &Reps,
+        quantity: Quantity,
+    ) -> Vec<Selected> {
+        self.model.selected(config, reps, quantity)
     }
 }
 
-impl WeveMarketMessages for SingleMarketMultiItemMaxBuy {
-    fn to_reqs(&self) -> Vec<MarketOrdersReq> {
-        self.0
+impl Guarded {
+    // The books the guards are checked against
+    fn guard_reqs(&self) -> [Req; 2] {
+        [
+            Req::MarketOrders(orders_req(self.type_id, &self.market, true)),
+            Req::MarketOrders(orders_req(self.type_id, &self.market, false)),
+        ]
+    }
+
+    // Returns why the book trips a guard, if it does
+    fn tripped(&self, reps: &Reps) -> Option<String> {
+        let buy = orders(reps, &orders_req(self.type_id, &self.market, true));
+        let sell = orders(reps, &orders_req(self.type_id, &self.market, false));
+        let max_buy = buy
             .iter()
-            .filter_map(
-                |option| option.map(
-                    |(type_id, _)| MarketOrdersReq {
-                        type_id: type_id,
-                        market: self.1.to_string(),
-                        buy: true,
+            .max_by(|o1, o2| order_f64(&o1.price, &o2.price));
+        let min_sell = sell
+            .iter()
+            .min_by(|o1, o2| rev_order_f64(&o1.price, &o2.price));
+
+        if let Some(max_spread) = self.guards.max_spread {
+            match (max_buy, min_sell) {
+                (Some(b), Some(s)) if b.price > 0.0 => {
+                    let spread: f64 = (s.price - b.price) / b.price;
+                    if spread > max_spread {
+                        return Some(format!(
+                            "spread of {:.1}% is wider than {:.1}%",
+                            spread * 100.0,
+                            max_spread * 100.0,
+                        ));
                     }
-                )
-            )
-            .collect()
+                },
+                _ => return Some("no spread without both buy and sell orders".to_string()),
+            }
+        }
+
+        let (book, top) = match self.buy {
+            true => (buy, max_buy),
+            false => (sell, min_sell),
+        };
+        if let Some(min_top_volume) = self.guards.min_top_volume {
+            let volume: f64 = top
+                .map(|o| o.price * o.quantity as f64)
+                .unwrap_or(0.0);
+            if volume < min_top_volume {
+                return Some(format!(
+                    "top of book holds {:.2} ISK, less than {:.2}",
+                    volume,
+                    min_top_volume,
+                ));
+            }
+        }
+        if let Some(min_orders) = self.guards.min_orders {
+            if book.len() < min_orders {
+                return Some(format!(
+                    "{} orders, fewer than {}",
+                    book.len(),
+                    min_orders,
+                ));
+            }
+        }
+        None
+    }
+}
+
+impl WeveMarketMessages for Guarded {
+    fn to_reqs(&self, config: &Config) -> Vec<Req> {
+        let mut reqs: Vec<Req> = self.model.to_reqs(config);
+        reqs.extend(self.guard_reqs());
+        reqs
+    }
+
+    fn get_price(&self, config: &Config, reps: &Reps, quantity: Quantity) -> Price {
+        match self.tripped(reps) {
+            Some(_) => Price::Rejected,
+            None => self.model.get_price_inner(config, reps, quantity),
+        }
+    }
+
+    fn price_source(&self, config: &Config) -> PriceSource {
+        PriceSource::new(ModelKind::Guarded, &self.description)
+            .with_market(&self.market)
+            .with_model(self.model.price_source(config))
     }
 
-    fn get_price(
+    fn price_source_for(
         &self,
-        reps: Vec<(MarketOrdersReq, MarketOrdersRep)>,
-    ) -> Price {
-        let mut price: f64 = 0.0;
-        for (req, rep) in reps {
-            for (type_id, quantity) in self
-                .0
-                .iter()
-                .filter_map(|option| option.as_ref())
-            {
-                if &req.type_id == type_id {
-                    match rep
-                        .market_orders
-                        .iter()
-                        .max_by(|o1, o2| order_f64(&o1.price, &o2.price))
-                    {
-                        Some(order) => price += order.price * quantity,
-                        None => return Price::Rejected,
-                    }
-                    continue;
-                }
-            }
+        config: &Config,
+        reps: &Reps,
+        quantity: Quantity,
+    ) -> PriceSource {
+        let ps = PriceSource::new(ModelKind::Guarded, &self.description)
+            .with_market(&self.market);
+        match self.tripped(reps) {
+            Some(reason) => ps.with_note(format!("rejected: {}", reason)),
+            None => ps.with_model(self.model.price_source_inner(config, reps, quantity)),
         }
-        Price::Accepted(price * self.2)
     }
 
-    fn price_source(&self) -> PriceSource {
-        self.3.to_string()
+    fn selected(
+        &self,
+        config: &Config,
+        reps: &Reps,
+        quantity: Quantity,
+    ) -> Vec<Selected> {
+        match self.tripped(reps) {
+            Some(_) => vec![],
+            None => self.model.selected(config, reps, quantity),
+        }
     }
 }
 
-impl SubSingleItemsMaxBuy {
-    fn sub_items(&self) -> impl Iterator<Item = (&PricingModel, &'static str, Quantity)> {
-        self.0
-            .iter()
-            .filter_map(
-                |s| match *s {
-                    ("", _) => None,
-                    (item, qnt) => match PM_MAP
-                        .get(self.1)
-                        .unwrap()
-                        .get(item)
-                    {
-                        Some(p) => Some((p, item, qnt)),
-                        None => Some((&PricingModel::Rejected, item, qnt)),
-                    }
+impl Smoothed {
+    fn snapshots_req(&self) -> SnapshotsReq {
+        SnapshotsReq {
+            type_id: self.type_id,
+            market: self.market.to_string(),
+            buy: self.buy,
+            days: self.days,
+        }
+    }
+
+    fn live_price(&self, reps: &Reps) -> Option<f64> {
+        let book = orders(reps, &orders_req(self.type_id, &self.market, self.buy));
+        match self.buy {
+            true => book
+                .iter()
+                .max_by(|o1, o2| order_f64(&o1.price, &o2.price)),
+            false => book
+                .iter()
+                .min_by(|o1, o2| rev_order_f64(&o1.price, &o2.price)),
+        }
+            .map(|order| order.price)
+    }
+
+    fn smoothed_price(&self, reps: &Reps) -> Option<f64> {
+        let mut prices: Vec<f64> = reps
+            .snapshots(&self.snapshots_req())?
+            .clone();
+        if prices.is_empty() {
+            return None;
+        }
+        match self.smoothing {
+            Smoothing::Mean => Some(
+                prices.iter().sum::<f64>() / prices.len() as f64
+            ),
+            Smoothing::Median => {
+                prices.sort_by(order_f64);
+                let mid: usize = prices.len() / 2;
+                match prices.len() % 2 {
+                    0 => Some((prices[mid - 1] + prices[mid]) / 2.0),
+                    _ => Some(prices[mid]),
                 }
-            )
+            },
+        }
     }
 }
 
-impl WeveMarketMessages for SubSingleItemsMaxBuy {
-    fn to_reqs(&self) -> Vec<MarketOrdersReq> {
-        let mut reqs: Vec<MarketOrdersReq> = Vec::with_capacity(self.0.len());
-        for (pm, item, _) in self.sub_items() {
-            match pm {
-                PricingModel::SingleMarketSingleItemMaxBuy(p) => reqs.extend(
-                    p.to_reqs()
-                ),
-                PricingModel::Rejected => (),
-                _ => panic!(
-                    "{} at location {} points to invalid PricingModel",
-                    item,
-                    self.1,
-                ),
-            };
+impl WeveMarketMessages for Smoothed {
+    fn to_reqs(&self, _config: &Config) -> Vec<Req> {
+        let mut reqs: Vec<Req> = vec![Req::Snapshots(self.snapshots_req())];
+        if self.cap.is_some() {
+            reqs.push(Req::MarketOrders(orders_req(self.type_id, &self.market, self.buy)));
         }
         reqs
     }
 
-    fn get_price(
+    fn get_price(&self, _config: &Config, reps: &Reps, _quantity: Quantity) -> Price {
+        let smoothed: f64 = match self.smoothed_price(reps) {
+            Some(price) => price,
+            None => return Price::Rejected, // No snapshots in the window
+        };
+        match self.cap {
+            Some(cap) => match self.live_price(reps) {
+                Some(live) => Price::Accepted(smoothed.min(live * cap) * self.modifier),
+                None => Price::Rejected, // Cannot cap without a live price
+            },
+            None => Price::Accepted(smoothed * self.modifier),
+        }
+    }
+
+    fn price_source(&self, _config: &Config) -> PriceSource {
+        let ps = PriceSource::new(ModelKind::Smoothed, &self.description)
+            .with_market(&self.market)
+            .with_modifier(self.modifier)
+            .with_note(format!(
+                "{}-day {:?} of {} side",
+                self.days,
+                self.smoothing,
+                side(self.buy),
+            ));
+        match self.cap {
+            Some(cap) => ps.with_note(format!("capped at x{} live", cap)),
+            None => ps,
+        }
+    }
+
+    // Only the live price is taken from orders, and only when capping
+    fn selected(
         &self,
-        reps: Vec<(MarketOrdersReq, MarketOrdersRep)>,
-    ) -> Price {
-        let mut priced: bool = false;
-        let mut price: f64 = 0.0;
-        for (req, rep) in reps {
-            for (pm, item, qnt) in self.sub_items() {
-                match pm {
-                    PricingModel::SingleMarketSingleItemMaxBuy(sipm) => {
-                        if req.type_id == sipm.0 {
-                            match sipm.get_price(vec![(req, rep)]) {
-                                Price::Accepted(siprice) => {
-                                    priced = true;
-                                    price += siprice * qnt;
-                                },
-                                Price::Rejected => (),
-                            }
-                            break;
-                        }
-                    },
-                    PricingModel::Rejected => (),
-                    _ => panic!(
-                        "{} at location {} points to invalid PricingModel",
-                        item,
-                        self.1,
-                    ),
-                }
-            }
+        _config: &Config,
+        reps: &Reps,
+        _quantity: Quantity,
+    ) -> Vec<Selected> {
+        match self.cap {
+            Some(_) => best(reps, orders_req(self.type_id, &self.market, self.buy))
+                .into_iter()
+                .collect(),
+            None => vec![],
         }
-        match priced {
-            true => Price::Accepted(price),
-            false => Price::Rejected,
+    }
+}
+
+impl WeveMarketMessages for MultiMarket {
+    fn to_reqs(&self, _config: &Config) -> Vec<Req> {
+        self.markets
+            .iter()
+            .map(|(market, _)| Req::MarketOrders(
+                orders_req(self.type_id, market, self.buy)
+            ))
+            .collect()
+    }
+
+    fn get_price(&self, _config: &Config, reps: &Reps, _quantity: Quantity) -> Price {
+        let prices: Vec<(&str, f64, f64)> = self.market_prices(reps);
+        if prices.is_empty() {
+            return Price::Rejected; // No market has orders
         }
+        let price: f64 = match self.combine {
+            Combine::Max => prices
+                .iter()
+                .map(|(_, price, _)| *price)
+                .fold(f64::MIN, f64::max),
+            Combine::Min => prices
+                .iter()
+                .map(|(_, price, _)| *price)
+                .fold(f64::MAX, f64::min),
+            Combine::WeightedAverage => {
+                let weight: f64 = prices
+                    .iter()
+                    .map(|(_, _, weight)| weight)
+                    .sum();
+                if weight <= 0.0 {
+                    return Price::Rejected;
+                }
+                prices
+                    .iter()
+                    .map(|(_, price, weight)| price * weight)
+                    .sum::<f64>() / weight
+            },
+        };
+        Price::Accepted(price * self.modifier)
+    }
+
+    fn price_source(&self, _config: &Config) -> PriceSource {
+        PriceSource::new(ModelKind::MultiMarket, &self.description)
+            .with_modifier(self.modifier)
+            .with_note(format!("{:?} of {} side", self.combine, side(self.buy)))
+    }
+
+    fn price_source_for(
+        &self,
+        config: &Config,
+        reps: &Reps,
+        _quantity: Quantity,
+    ) -> PriceSource {
+        self
+            .market_prices(reps)
+            .into_iter()
+            .fold(self.price_source(config), |ps, (market, price, weight)| ps
+                .with_note(format!(
+                    "{} top of book {:.2}, weight {}",
+                    market,
+                    price,
+                    weight,
+                )))
+    }
+
+    // Every market's top of the book when averaging, otherwise the one chosen
+    fn selected(
+        &self,
+        _config: &Config,
+        reps: &Reps,
+        _quantity: Quantity,
+    ) -> Vec<Selected> {
+        let prices: Vec<(&str, f64, f64)> = self.market_prices(reps);
+        let chosen: Option<f64> = match self.combine {
+            Combine::Max => prices
+                .iter()
+                .map(|(_, price, _)| *price)
+                .reduce(f64::max),
+            Combine::Min => prices
+                .iter()
+                .map(|(_, price, _)| *price)
+                .reduce(f64::min),
+            Combine::WeightedAverage => None,
+        };
+        prices
+            .iter()
+            .filter(|(_, price, _)| chosen.map_or(true, |chosen| *price == chosen))
+            .filter_map(|(market, _, _)| best(reps, orders_req(self.type_id, market, self.buy)))
+            .collect()
     }
+}
+
+fn sub_items<'c>(
+    config: &'c Config,
+    location: &'c str,
+    items: &'c [(ItemName, Quantity)],
+) -> impl Iterator<Item = (&'c PricingModel, &'c str, Quantity)> + 'c {
+    items
+        .iter()
+        .map(
+            move |(item, qnt)| match config.get(location, item) {
+                Some(p) => (p, item.as_str(), *qnt),
+                None => (&PricingModel::Rejected, item.as_str(), *qnt),
+            }
+        )
+}
 
-    fn price_source(&self) -> PriceSource {
-        let mut ps = String::new();
-        let mut first = true;
-        for (pm, item, qnt) in self.sub_items() {
-            ps.push_str(&format!(
-                "{{\"item\":\"{}\",\"quantity\":{},\"description\":\"{}\"}},",
+// Returns a description of the first cycle or depth overrun beneath items
+fn check_sub_items<'c>(
+    config: &'c Config,
+    location: &'c str,
+    items: &'c [(ItemName, Quantity)],
+    path: &mut Vec<(&'c str, &'c str)>,
+) -> Result<(), String> {
+    if path.len() >= MAX_SUB_DEPTH {
+        return Err(format!(
+            "sub items at location {} nest deeper than {}",
+            location,
+            MAX_SUB_DEPTH,
+        ));
+    }
+    for (pm, item, _) in sub_items(config, location, items) {
+        if path.contains(&(location, item)) {
+            return Err(format!(
+                "{} at location {} contains itself",
                 item,
-                qnt,
-                pm.price_source(),
+                location,
             ));
-            if first {
-                first = false;
-            }
         }
-        if !first { // remove trailing comma unless self.0 is empty
-            ps.pop();
+        path.push((location, item));
+        for (sub_location, sub_items) in pm.sub_item_lists() {
+            check_sub_items(config, sub_location, sub_items, path)?;
         }
-        format!(
-            "MP{{\"description\":\"{}\",\"values\":[{}]}}",
-            self.2,
-            ps,
-        )
+        path.pop();
+    }
+    Ok(())
+}
+
+fn no_reprocess_yield(location: &str) -> String {
+    format!("no reprocessing yield at location {}", location)
+}
+
+fn side(buy: bool) -> &'static str {
+    match buy {
+        true => "buy",
+        false => "sell",
     }
 }
 
-async fn get_market_orders(
-    mut client: Client,
-    req: MarketOrdersReq,
-) -> Result<(MarketOrdersReq, MarketOrdersRep), Error> {
-    Ok((req.clone(), client
+fn orders_req(type_id: TypeId, market: &str, buy: bool) -> MarketOrdersReq {
+    MarketOrdersReq {
+        type_id: type_id,
+        market: market.to_string(),
+        buy: buy,
+    }
+}
+
+// Returns the top of req's book, the highest buy or the lowest sell
+fn best(reps: &Reps, req: MarketOrdersReq) -> Option<Selected> {
+    let book = orders(reps, &req);
+    let order = match req.buy {
+        true => book
+            .iter()
+            .max_by(|o1, o2| order_f64(&o1.price, &o2.price)),
+        false => book
+            .iter()
+            .min_by(|o1, o2| rev_order_f64(&o1.price, &o2.price)),
+    }?
+        .clone();
+    Some((req, order))
+}
+
+// Returns an empty book if there is no reply for req
+fn orders<'r>(reps: &'r Reps, req: &MarketOrdersReq) -> &'r [MarketOrder] {
+    reps
         .market_orders(req)
-        .await?
-        .into_inner()
-    ))
+        .map(|rep| rep.market_orders.as_slice())
+        .unwrap_or(&[])
+}
+
+// Drops orders whose price is further than max_dev quantity-weighted standard
+// deviations from the quantity-weighted mean price
+fn drop_outliers(orders: Vec<MarketOrder>, max_dev: f64) -> Vec<MarketOrder> {
+    let volume: f64 = orders
+        .iter()
+        .map(|o| o.quantity as f64)
+        .sum();
+    if volume <= 0.0 {
+        return orders;
+    }
+    let mean: f64 = orders
+        .iter()
+        .map(|o| o.price * o.quantity as f64)
+        .sum::<f64>() / volume;
+    let std_dev: f64 = (orders
+        .iter()
+        .map(|o| (o.price - mean).powi(2) * o.quantity as f64)
+        .sum::<f64>() / volume)
+        .sqrt();
+    orders
+        .into_iter()
+        .filter(|o| (o.price - mean).abs() <= max_dev * std_dev)
+        .collect()
 }
 
 fn order_f64(v1: &f64, v2: &f64) -> std::cmp::Ordering {
@@ -372,3 +1955,258 @@ fn rev_order_f64(v1: &f64, v2: &f64) -> std::cmp::Ordering {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::Rep;
+
+    fn order(price: f64, quantity: i32) -> MarketOrder {
+        MarketOrder {
+            price: price,
+            quantity: quantity,
+        }
+    }
+
+    fn book(buy: bool, orders: Vec<MarketOrder>) -> Reps {
+        let mut reps: Reps = Reps::default();
+        reps.insert(
+            Req::MarketOrders(orders_req(34, "Jita", buy)),
+            Rep::MarketOrders(MarketOrdersRep {
+                market_orders: orders,
+            }),
+        );
+        reps
+    }
+
+    fn accepted(price: Price) -> f64 {
+        match price {
+            Price::Accepted(f) => f,
+            Price::Rejected => panic!("rejected"),
+        }
+    }
+
+    fn assert_near(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    fn depth(buy: bool, unfilled: Unfilled) -> SingleMarketSingleItemDepth {
+        SingleMarketSingleItemDepth {
+            type_id: 34,
+            market: "Jita".to_string(),
+            buy: buy,
+            unfilled: unfilled,
+            modifier: 1.0,
+            description: String::new(),
+        }
+    }
+
+    fn percentile(percentile: f64) -> SingleMarketSingleItemPercentile {
+        SingleMarketSingleItemPercentile {
+            type_id: 34,
+            market: "Jita".to_string(),
+            buy: true,
+            percentile: percentile,
+            max_std_devs: None,
+            modifier: 1.0,
+            description: String::new(),
+        }
+    }
+
+    fn tiered(tiers: Vec<(f64, PriceMod)>) -> Tiered {
+        Tiered {
+            model: Box::new(PricingModel::Rejected),
+            basis: TierBasis::Quantity,
+            tiers: tiers,
+            description: String::new(),
+        }
+    }
+
+    fn max_buy() -> PricingModel {
+        PricingModel::SingleMarketSingleItemMaxBuy(SingleMarketSingleItemMaxBuy {
+            type_id: 34,
+            market: "Jita".to_string(),
+            modifier: 1.0,
+            description: String::new(),
+        })
+    }
+
+    fn adjusted() -> PricingModel {
+        PricingModel::AdjustedPriceModel(AdjustedPriceModel {
+            type_id: 34,
+            modifier: 1.0,
+            description: String::new(),
+        })
+    }
+
+    #[test]
+    fn snapshots_uncapped_smoothed_books() {
+        let config: Config = Config::default();
+        let smoothed = |cap: Option<f64>| PricingModel::Smoothed(Smoothed {
+            type_id: 34,
+            market: "Jita".to_string(),
+            buy: true,
+            days: 7,
+            smoothing: Smoothing::Median,
+            cap: cap,
+            modifier: 1.0,
+            description: String::new(),
+        });
+        let jita: MarketOrdersReq = orders_req(34, "Jita", true);
+        assert_eq!(smoothed(None).snapshot_reqs(&config), vec![jita.clone()]);
+        assert!(smoothed(Some(1.1)).snapshot_reqs(&config).contains(&jita));
+        assert_eq!(max_buy().snapshot_reqs(&config), vec![jita]);
+        assert!(adjusted().snapshot_reqs(&config).is_empty());
+    }
+
+    #[test]
+    fn depth_fills_from_the_best_price() {
+        let config: Config = Config::default();
+        let buys: Reps = book(true, vec![order(90.0, 10), order(100.0, 10), order(80.0, 10)]);
+        let p: SingleMarketSingleItemDepth = depth(true, Unfilled::Reject);
+        assert_near(accepted(p.get_price(&config, &buys, 15.0)), (1000.0 + 450.0) / 15.0);
+        assert_near(accepted(p.get_price(&config, &buys, 0.0)), 100.0);
+
+        let sells: Reps = book(false, vec![order(5.0, 10), order(3.0, 10)]);
+        let p: SingleMarketSingleItemDepth = depth(false, Unfilled::Reject);
+        assert_near(accepted(p.get_price(&config, &sells, 10.0)), 3.0);
+    }
+
+    #[test]
+    fn depth_prices_unfilled_quantity() {
+        let config: Config = Config::default();
+        let reps: Reps = book(true, vec![order(100.0, 10), order(90.0, 10), order(80.0, 10)]);
+        assert!(matches!(
+            depth(true, Unfilled::Reject).get_price(&config, &reps, 40.0),
+            Price::Rejected,
+        ));
+        assert_near(
+            accepted(depth(true, Unfilled::WorstPrice).get_price(&config, &reps, 40.0)),
+            (1000.0 + 900.0 + 800.0 + 800.0) / 40.0,
+        );
+        assert!(matches!(
+            depth(true, Unfilled::WorstPrice).get_price(&config, &book(true, vec![]), 1.0),
+            Price::Rejected,
+        ));
+    }
+
+    #[test]
+    fn percentile_clears_past_small_orders() {
+        let reps: Reps = book(true, vec![order(100.0, 1), order(90.0, 10), order(80.0, 10)]);
+        assert_near(percentile(0.05).clearing(&reps).unwrap().price, 90.0);
+        assert_near(percentile(0.0).clearing(&reps).unwrap().price, 100.0);
+        assert_near(percentile(1.0).clearing(&reps).unwrap().price, 80.0);
+        assert!(percentile(0.5).clearing(&book(true, vec![])).is_none());
+    }
+
+    #[test]
+    fn drop_outliers_keeps_the_bulk_of_the_book() {
+        let orders: Vec<MarketOrder> = vec![order(10.0, 100), order(11.0, 100), order(1000.0, 1)];
+        let kept: Vec<MarketOrder> = drop_outliers(orders.clone(), 2.0);
+        assert_eq!(kept, orders[..2].to_vec());
+        assert_eq!(drop_outliers(orders.clone(), 1000.0), orders);
+        assert_eq!(drop_outliers(vec![order(10.0, 0)], 1.0), vec![order(10.0, 0)]);
+    }
+
+    #[test]
+    fn marginal_weighs_each_tier_by_the_amount_inside_it() {
+        let p: Tiered = tiered(vec![(0.0, 1.0), (100.0, 0.5)]);
+        assert_near(p.marginal(50.0), 1.0);
+        assert_near(p.marginal(100.0), 1.0);
+        assert_near(p.marginal(200.0), 0.75);
+        assert_near(p.marginal(0.0), 1.0);
+
+        // The first tier covers everything below its lower bound too
+        let p: Tiered = tiered(vec![(10.0, 0.9), (20.0, 0.8)]);
+        assert_near(p.marginal(10.0), 0.9);
+        assert_near(p.marginal(40.0), (20.0 * 0.9 + 20.0 * 0.8) / 40.0);
+    }
+
+    #[test]
+    fn check_sub_items_finds_cycles() {
+        let config: Config = serde_json::from_str(r#"{"locations": {"L": {
+            "A": {"SubSingleItemsMaxBuy": {"items": [["B", 1.0]], "location": "L", "description": ""}},
+            "B": {"SubSingleItemsMaxBuy": {"items": [["A", 1.0]], "location": "L", "description": ""}},
+            "C": {"SubSingleItemsMaxBuy": {"items": [["D", 2.0]], "location": "L", "description": ""}},
+            "D": {"AdjustedPriceModel": {"type_id": 34, "modifier": 1.0, "description": ""}}
+        }}}"#).unwrap();
+        let items = |item: &str| match config.get("L", item) {
+            Some(PricingModel::SubSingleItemsMaxBuy(p)) => p.items.clone(),
+            _ => unreachable!(),
+        };
+        let (a, c) = (items("A"), items("C"));
+        assert_eq!(
+            check_sub_items(&config, "L", &a, &mut Vec::new()),
+            Err("B at location L contains itself".to_string()),
+        );
+        assert_eq!(check_sub_items(&config, "L", &c, &mut Vec::new()), Ok(()));
+    }
+
+    #[test]
+    fn fallback_is_unavailable_only_up_to_the_accepting_step() {
+        let config: Config = Config::default();
+        let fallback: PricingModel = PricingModel::Fallback(vec![max_buy(), adjusted()]);
+        let adjusted_req: Req = Req::AdjustedPrice(AdjustedPriceReq { type_id: 34 });
+
+        let mut reps: Reps = book(true, vec![order(100.0, 1)]);
+        reps.fail(adjusted_req.clone(), "timed out");
+        assert_eq!(fallback.error(&config, &reps, 1.0), None);
+
+        let mut reps: Reps = book(true, vec![]);
+        reps.fail(adjusted_req, "timed out");
+        assert_eq!(fallback.error(&config, &reps, 1.0), Some(&"timed out".to_string()));
+
+        let mut reps: Reps = Reps::default();
+        reps.fail(Req::MarketOrders(orders_req(34, "Jita", true)), "timed out");
+        assert_eq!(fallback.error(&config, &reps, 1.0), Some(&"timed out".to_string()));
+    }
+
+    #[test]
+    fn sub_item_fallbacks_are_unavailable_only_up_to_the_accepting_step() {
+        let mut config: Config = serde_json::from_str(r#"{"locations": {"L": {
+            "A": {"SubSingleItemsMaxBuy": {"items": [["B", 2.0]], "location": "L", "description": ""}}
+        }}}"#).unwrap();
+        config
+            .locations
+            .get_mut("L")
+            .unwrap()
+            .insert("B".to_string(), PricingModel::Fallback(vec![max_buy(), adjusted()]));
+        let composite: &PricingModel = config.get("L", "A").unwrap();
+        let adjusted_req: Req = Req::AdjustedPrice(AdjustedPriceReq { type_id: 34 });
+
+        let mut reps: Reps = book(true, vec![order(100.0, 1)]);
+        reps.fail(adjusted_req.clone(), "timed out");
+        assert_eq!(composite.error(&config, &reps, 1.0), None);
+
+        let mut reps: Reps = book(true, vec![]);
+        reps.fail(adjusted_req, "timed out");
+        assert_eq!(composite.error(&config, &reps, 1.0), Some(&"timed out".to_string()));
+    }
+
+    #[test]
+    fn reprocessing_yields_are_set_where_the_ore_is_reprocessed() {
+        let config: Config = serde_json::from_str(r#"{"locations": {
+            "Hub": {"Tritanium": {"AdjustedPriceModel": {"type_id": 34, "modifier": 1.0, "description": ""}}},
+            "A": {"Veldspar": {"Reprocessing": {"outputs": [["Tritanium", 400.0]], "portion_size": 100.0,
+                "location": "Hub", "yield_location": "A", "description": ""}}},
+            "B": {"Veldspar": {"Reprocessing": {"outputs": [["Tritanium", 400.0]], "portion_size": 100.0,
+                "location": "Hub", "yield_location": "B", "description": ""}}}
+        }, "reprocess_yields": {
+            "A": {"structure": 0.5, "skills": 0.0, "implant": 0.0},
+            "B": {"structure": 0.75, "skills": 0.0, "implant": 0.0}
+        }}"#).unwrap();
+        let mut reps: Reps = Reps::default();
+        reps.insert(
+            Req::AdjustedPrice(AdjustedPriceReq { type_id: 34 }),
+            Rep::AdjustedPrice(AdjustedPriceRep { adjusted_price: 5.0 }),
+        );
+        let price = |location: &str| accepted(config
+            .get(location, "Veldspar")
+            .unwrap()
+            .get_price(&config, &reps, 100.0)
+            .0
+        );
+        assert_near(price("A"), 400.0 * 0.5 * 5.0 / 100.0);
+        assert_near(price("B"), 400.0 * 0.75 * 5.0 / 100.0);
+    }
+}
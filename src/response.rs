@@ -1,9 +1,12 @@
 use crate::{
-    PriceSource,
+    {ItemName},
     pricing::Price,
+    source::{PriceSource, deserialize_stored},
+    config::Config,
     error::Error,
+    fees,
+    freight,
     item::Item,
-    static_map::PRICE_VERSION,
 };
 
 use std::{
@@ -20,9 +23,17 @@ use serde_json;
 pub struct Response {
     pub accepted: Vec<AcceptedResultItem>,
     pub rejected: Vec<RejectedResultItem>,
+    #[serde(default)]
+    pub unavailable: Vec<UnavailableResultItem>,
+    #[serde(default)]
+    pub partial: bool, // true if any item is unavailable
     pub hash: String,
     pub location: String,
     pub sum: f64,
+    #[serde(default)]
+    pub volume: f64,
+    #[serde(default)]
+    pub freight_surcharge: f64, // tops freight up to the minimum fee
     pub timestamp: u64,
     pub version: String,
 }
@@ -31,46 +42,173 @@ pub struct Response {
 pub struct AcceptedResultItem {
     #[serde(flatten)]
     pub item: Item,
+    #[serde(default)]
+    pub price_gross: f64,
+    #[serde(default)]
+    pub deductions: Vec<Deduction>,
     pub price_per: f64,
     pub price_total: f64,
-    pub source: String,
+    #[serde(deserialize_with = "deserialize_stored")]
+    pub source: PriceSource,
+}
+
+// A per unit amount taken off the gross price
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Deduction {
+    pub kind: String,
+    pub amount: f64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RejectedResultItem {
     #[serde(flatten)]
     pub item: Item,
-    pub source: String,
+    #[serde(deserialize_with = "deserialize_stored")]
+    pub source: PriceSource,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub suggestions: Vec<ItemName>, // closest names, if none matched
+}
+
+// An item that could not be priced because a request for it failed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnavailableResultItem {
+    #[serde(flatten)]
+    pub item: Item,
+    pub reason: String,
 }
 
 impl Response {
-    pub fn with_capacity(capacity: usize, location: String) -> Self {
+    pub fn with_capacity(
+        capacity: usize,
+        location: String,
+        version: String,
+    ) -> Self {
         Response {
             accepted: Vec::with_capacity(capacity),
             rejected: Vec::with_capacity(capacity),
+            unavailable: Vec::new(),
+            partial: false,
             hash: String::new(),
             location: location,
             sum: 0.0,
+            volume: 0.0,
+            freight_surcharge: 0.0,
             timestamp: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .expect("Now < UnixEpoch?")
                 .as_secs(),
-            version: PRICE_VERSION.to_string(),
+            version: version,
         }
     }
 
-    pub fn push(&mut self, item: Item, price: Price, source: PriceSource) {
-        match price {
-            Price::Accepted(f) => {
-                self.accepted.push((item, f, source).into());
-                self.sum += self
-                    .accepted
-                    .last()
-                    .unwrap()
-                    .price_total
+    pub fn push(
+        &mut self,
+        config: &Config,
+        item: Item,
+        price: Price,
+        source: PriceSource,
+    ) {
+        let f: f64 = match price {
+            Price::Accepted(f) => f,
+            Price::Rejected => {
+                self.rejected.push((item, source).into());
+                return;
             },
-            Price::Rejected => self.rejected.push((item, source).into()),
         };
+        let mut deductions: Vec<Deduction> = fees::deductions(
+            config,
+            &self.location,
+            f,
+        );
+        deductions.extend(freight::deductions(
+            config,
+            &self.location,
+            &item.name,
+            f,
+        ));
+        let deducted: f64 = deductions
+            .iter()
+            .map(|d| d.amount)
+            .sum();
+        if deducted > f { // Costs more to haul and sell than it is worth
+            self.rejected.push((item, source.with_note(format!(
+                "deductions of {:.2} exceed the gross price of {:.2}",
+                deducted,
+                f,
+            ))).into());
+            return;
+        }
+        self.volume += freight::volume(config, &item.name) * item.quantity;
+        self.accepted.push((item, f, deductions, source).into());
+        self.sum += self
+            .accepted
+            .last()
+            .unwrap()
+            .price_total
+    }
+
+    pub fn push_unavailable(&mut self, item: Item, reason: String) {
+        self.unavailable.push(UnavailableResultItem {
+            item: item,
+            reason: reason,
+        });
+        self.partial = true;
+    }
+
+    // Charges whatever freight the accepted items did not cover up to the
+    // location's minimum fee, call this once all items are pushed. Rejects
+    // every accepted item if the charge is more than they add up to.
+    pub fn with_freight_minimum(&mut self, config: &Config) {
+        if self.accepted.is_empty() {
+            return;
+        }
+        let charged: f64 = self
+            .accepted
+            .iter()
+            .flat_map(|a| a
+                .deductions
+                .iter()
+                .filter(|d| d.kind == "freight")
+                .map(move |d| d.amount * a.item.quantity)
+            )
+            .sum();
+        let minimum: f64 = freight::minimum_fee(config, &self.location);
+        if charged < minimum {
+            self.freight_surcharge = minimum - charged;
+            if self.freight_surcharge > self.sum { // Not worth hauling at all
+                let note: String = format!(
+                    "freight surcharge of {:.2} to the minimum fee exceeds \
+                    the accepted total of {:.2}",
+                    self.freight_surcharge,
+                    self.sum,
+                );
+                for accepted in std::mem::take(&mut self.accepted) {
+                    self.rejected.push((
+                        accepted.item,
+                        accepted.source.with_note(note.clone()),
+                    ).into());
+                }
+                self.sum = 0.0;
+                self.volume = 0.0;
+                self.freight_surcharge = 0.0;
+                return;
+            }
+            self.sum -= self.freight_surcharge;
+        }
+    }
+
+    // Offers the closest priced names for every rejected item that matched
+    // no name or alias at the location
+    pub fn with_suggestions(&mut self, config: &Config) {
+        for rejected in self.rejected.iter_mut() {
+            if config
+                .resolve(&self.location, &rejected.item.name)
+                .is_none()
+            {
+                rejected.suggestions = config
+                    .suggestions(&self.location, &rejected.item.name);
+            }
+        }
     }
 
     pub fn to_stdout(&self) -> Result<(), Error> {
@@ -98,6 +236,11 @@ impl Response {
             .name
             .as_str()
             .cmp(b.item.name.as_str()));
+        self.unavailable.sort_by(|a, b| a
+            .item
+            .name
+            .as_str()
+            .cmp(b.item.name.as_str()));
     }
 
     pub fn to_json(&self) -> Result<String, Error> {
@@ -109,6 +252,7 @@ impl Response {
 impl Hash for Response {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.location.hash(state);
+        self.version.hash(state); // so the hash names the rules priced under
         for item in self
             .accepted
             .iter()
@@ -125,16 +269,33 @@ impl Hash for Response {
             item.item.name.hash(state);
             to_fstring(&item.item.quantity).hash(state);
         }
+        to_fstring(&self.freight_surcharge).hash(state);
+    }
+}
+
+impl Deduction {
+    pub fn new(kind: &str, amount: f64) -> Self {
+        Deduction {
+            kind: kind.to_string(),
+            amount: amount,
+        }
     }
 }
 
-impl From<(Item, f64, PriceSource)> for AcceptedResultItem {
-    fn from(value: (Item, f64, PriceSource)) -> Self {
+impl From<(Item, f64, Vec<Deduction>, PriceSource)> for AcceptedResultItem {
+    fn from(value: (Item, f64, Vec<Deduction>, PriceSource)) -> Self {
+        let net: f64 = value.1 - value
+            .2
+            .iter()
+            .map(|d| d.amount)
+            .sum::<f64>();
         AcceptedResultItem {
-            price_per: value.1,
-            price_total: value.0.quantity * value.1,
+            price_gross: value.1,
+            price_per: net,
+            price_total: value.0.quantity * net,
             item: value.0,
-            source: value.2.to_string(),
+            deductions: value.2,
+            source: value.3,
         }
     }
 }
@@ -143,7 +304,8 @@ impl From<(Item, PriceSource)> for RejectedResultItem {
     fn from(value: (Item, PriceSource)) -> Self {
         RejectedResultItem {
             item: value.0,
-            source: value.1.to_string(),
+            source: value.1,
+            suggestions: Vec::new(),
         }
     }
 }
@@ -151,3 +313,70 @@ impl From<(Item, PriceSource)> for RejectedResultItem {
 fn to_fstring(f: &f64) -> String {
     format!("{}", f)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::ModelKind;
+
+    fn response(minimum_fee: f64, prices: &[(&str, f64)]) -> Response {
+        versioned(minimum_fee, prices, "v")
+    }
+
+    fn versioned(minimum_fee: f64, prices: &[(&str, f64)], version: &str) -> Response {
+        let config: Config = serde_json::from_str(&format!(
+            r#"{{"locations": {{}}, "freight": {{"L": {{
+                "isk_per_m3": 100.0, "collateral": 0.0, "minimum_fee": {}
+            }}}}, "volumes": {{"Tritanium": 0.01}}}}"#,
+            minimum_fee,
+        )).unwrap();
+        let mut response: Response = Response::with_capacity(
+            prices.len(),
+            "L".to_string(),
+            version.to_string(),
+        );
+        for (name, price) in prices {
+            response.push(
+                &config,
+                Item { name: name.to_string(), quantity: 1.0 },
+                Price::Accepted(*price),
+                PriceSource::new(ModelKind::AdjustedPrice, ""),
+            );
+        }
+        response.with_freight_minimum(&config);
+        response
+    }
+
+    #[test]
+    fn freight_minimum_tops_up_freight() {
+        let response: Response = response(10.0, &[("Tritanium", 100.0)]);
+        assert_eq!(response.freight_surcharge, 9.0);
+        assert_eq!(response.sum, 100.0 - 1.0 - 9.0);
+    }
+
+    #[test]
+    fn freight_minimum_over_the_total_rejects_the_appraisal() {
+        let response: Response = response(5000000.0, &[("Tritanium", 5.0), ("Rifter", 10.0)]);
+        assert!(response.accepted.is_empty());
+        assert_eq!(response.rejected.len(), 2);
+        assert_eq!(response.sum, 0.0);
+        assert_eq!(response.freight_surcharge, 0.0);
+    }
+
+    #[test]
+    fn hash_covers_the_freight_surcharge() {
+        let hash = |minimum_fee: f64| response(minimum_fee, &[("Tritanium", 100.0)])
+            .with_hash_key()
+            .to_string();
+        assert_ne!(hash(10.0), hash(20.0));
+    }
+
+    #[test]
+    fn hash_covers_the_version() {
+        let hash = |version: &str| versioned(10.0, &[("Tritanium", 100.0)], version)
+            .with_hash_key()
+            .to_string();
+        assert_eq!(hash("a@1"), hash("a@1"));
+        assert_ne!(hash("a@1"), hash("a@2"));
+    }
+}
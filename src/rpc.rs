@@ -0,0 +1,280 @@
+use crate::{
+    Client,
+    history::{SnapshotsReq, snapshot_prices, now},
+    cache::Cache,
+    error::Error,
+    proto::*,
+};
+
+use std::{
+    collections::{HashMap, HashSet},
+    env::var,
+    future::Future,
+    str::FromStr,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use futures::stream::{StreamExt, futures_unordered::FuturesUnordered};
+use firestore::FirestoreDb;
+use tokio::{sync::Semaphore, time::{Instant, timeout_at}};
+
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+static IN_FLIGHT: OnceLock<Semaphore> = OnceLock::new();
+
+// How WeveMarket RPCs are sent, read once from the environment
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub max_in_flight: usize,
+    pub timeout: Duration, // per attempt
+    pub retries: u32,
+    pub backoff: Duration, // before the first retry, doubling after each
+    pub deadline: Duration, // for all of an appraisal's requests, see get_prices
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Req {
+    MarketOrders(MarketOrdersReq),
+    AdjustedPrice(AdjustedPriceReq),
+    SystemIndex(SystemIndexReq),
+    Snapshots(SnapshotsReq), // Served from the snapshot store, not WeveMarket
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rep {
+    MarketOrders(MarketOrdersRep),
+    AdjustedPrice(AdjustedPriceRep),
+    SystemIndex(SystemIndexRep),
+    Snapshots(Vec<f64>),
+}
+
+// The replies to every request of an appraisal, why the requests that failed
+// did, and when each reply's data was fetched, looked up by request
+#[derive(Debug, Clone, Default)]
+pub struct Reps(HashMap<Req, Rep>, HashMap<Req, String>, HashMap<Req, u64>);
+
+impl Reps {
+    // Returns why the first failed request of reqs failed
+    pub fn error(&self, reqs: &[Req]) -> Option<&String> {
+        reqs
+            .iter()
+            .find_map(|req| self.1.get(req))
+    }
+
+    // Returns when the reply to req was fetched, as a unix timestamp, which
+    // is earlier than the appraisal for replies served from the cache
+    pub fn fetched(&self, req: &Req) -> Option<u64> {
+        self.2.get(req).copied()
+    }
+
+    pub fn market_orders(&self, req: &MarketOrdersReq) -> Option<&MarketOrdersRep> {
+        match self.0.get(&Req::MarketOrders(req.clone())) {
+            Some(Rep::MarketOrders(rep)) => Some(rep),
+            _ => None,
+        }
+    }
+
+    pub fn adjusted_price(&self, req: &AdjustedPriceReq) -> Option<&AdjustedPriceRep> {
+        match self.0.get(&Req::AdjustedPrice(req.clone())) {
+            Some(Rep::AdjustedPrice(rep)) => Some(rep),
+            _ => None,
+        }
+    }
+
+    pub fn system_index(&self, req: &SystemIndexReq) -> Option<&SystemIndexRep> {
+        match self.0.get(&Req::SystemIndex(req.clone())) {
+            Some(Rep::SystemIndex(rep)) => Some(rep),
+            _ => None,
+        }
+    }
+
+    pub fn snapshots(&self, req: &SnapshotsReq) -> Option<&Vec<f64>> {
+        match self.0.get(&Req::Snapshots(req.clone())) {
+            Some(Rep::Snapshots(rep)) => Some(rep),
+            _ => None,
+        }
+    }
+}
+
+// Replies and failures for pricing without fetching
+#[cfg(test)]
+impl Reps {
+    pub fn insert(&mut self, req: Req, rep: Rep) {
+        self.0.insert(req, rep);
+    }
+
+    pub fn fail(&mut self, req: Req, reason: &str) {
+        self.1.insert(req, reason.to_string());
+    }
+}
+
+impl IntoIterator for Reps {
+    type Item = (Req, Rep);
+    type IntoIter = std::collections::hash_map::IntoIter<Req, Rep>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl Limits {
+    fn from_env() -> Self {
+        Limits {
+            // No permits would leave every RPC waiting for one forever
+            max_in_flight: read_or::<usize>("BBBE_RPCMAXINFLIGHT", 64).max(1),
+            timeout: Duration::from_millis(read_or("BBBE_RPCTIMEOUT", 10000)),
+            retries: read_or("BBBE_RPCRETRIES", 3),
+            backoff: Duration::from_millis(read_or("BBBE_RPCBACKOFF", 100)),
+            deadline: Duration::from_millis(read_or("BBBE_APPRAISALDEADLINE", 30000)),
+        }
+    }
+}
+
+pub fn limits() -> &'static Limits {
+    LIMITS.get_or_init(Limits::from_env)
+}
+
+// Returns each request once, in the order it was first seen
+pub fn plan(reqs: impl IntoIterator<Item = Req>) -> Vec<Req> {
+    let mut seen: HashSet<Req> = HashSet::new();
+    reqs
+        .into_iter()
+        .filter(|req| seen.insert(req.clone()))
+        .collect()
+}
+
+// A request failing, or still running at deadline, does not fail the others
+pub async fn get_reps(
+    client: Client,
+    db: &FirestoreDb,
+    cache: Option<&Cache>,
+    deadline: Option<Instant>,
+    reqs: Vec<Req>,
+) -> Reps {
+    let mut stream = reqs
+        .into_iter()
+        .map(|req| {
+            let client: Client = client.clone();
+            async move {
+                let result: Result<(Rep, u64), Error> = match deadline {
+                    Some(deadline) => timeout_at(
+                        deadline,
+                        request(client, db, cache, &req),
+                    )
+                        .await
+                        .unwrap_or(Err(Error::AppraisalDeadline)),
+                    None => request(client, db, cache, &req).await,
+                };
+                (req, result)
+            }
+        })
+        .collect::<FuturesUnordered<_>>();
+
+    let mut reps: Reps = Reps::default();
+    while let Some((req, result)) = stream.next().await {
+        match result {
+            Ok((rep, fetched)) => {
+                reps.2.insert(req.clone(), fetched);
+                reps.0.insert(req, rep);
+            },
+            Err(e) => {
+                reps.1.insert(req, format!("{}", e));
+            },
+        }
+    }
+    reps
+}
+
+async fn request(
+    client: Client,
+    db: &FirestoreDb,
+    cache: Option<&Cache>,
+    req: &Req,
+) -> Result<(Rep, u64), Error> {
+    Ok(match req {
+        Req::MarketOrders(r) => match cache {
+            Some(cache) => {
+                let (rep, fetched) = cache
+                    .get_or_fetch(r, || market_orders(client, r.clone()))
+                    .await?;
+                (Rep::MarketOrders(rep), fetched)
+            },
+            None => (
+                Rep::MarketOrders(market_orders(client, r.clone()).await?),
+                now(),
+            ),
+        },
+        Req::AdjustedPrice(r) => (
+            Rep::AdjustedPrice(call(|| {
+                let (mut client, r) = (client.clone(), r.clone());
+                async move { client.adjusted_price(r).await }
+            }).await?),
+            now(),
+        ),
+        Req::SystemIndex(r) => (
+            Rep::SystemIndex(call(|| {
+                let (mut client, r) = (client.clone(), r.clone());
+                async move { client.system_index(r).await }
+            }).await?),
+            now(),
+        ),
+        Req::Snapshots(r) => (
+            Rep::Snapshots(snapshot_prices(db, r).await?),
+            now(),
+        ),
+    })
+}
+
+async fn market_orders(
+    client: Client,
+    req: MarketOrdersReq,
+) -> Result<MarketOrdersRep, Error> {
+    call(|| {
+        let (mut client, req) = (client.clone(), req.clone());
+        async move { client.market_orders(req).await }
+    }).await
+}
+
+// Sends an RPC within the in flight limit, timing out each attempt and
+// retrying the ones that may succeed on another try
+async fn call<T, F, Fut>(mut rpc: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<tonic::Response<T>, tonic::Status>>,
+{
+    let limits: &Limits = limits();
+    let mut backoff: Duration = limits.backoff;
+    let mut attempt: u32 = 0;
+    loop {
+        let result = {
+            let _permit = IN_FLIGHT
+                .get_or_init(|| Semaphore::new(limits.max_in_flight))
+                .acquire()
+                .await
+                .unwrap(); // The semaphore is never closed
+            tokio::time::timeout(limits.timeout, rpc()).await
+        };
+        let err: Error = match result {
+            Ok(Ok(rep)) => return Ok(rep.into_inner()),
+            Ok(Err(status)) => match status.code() {
+                tonic::Code::Unavailable
+                | tonic::Code::DeadlineExceeded => Error::GRPCStatus(status),
+                _ => return Err(Error::GRPCStatus(status)),
+            },
+            Err(_) => Error::GRPCTimeout,
+        };
+        if attempt >= limits.retries {
+            return Err(err);
+        }
+        attempt += 1;
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+}
+
+fn read_or<T: FromStr>(key: &str, default: T) -> T {
+    var(key)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(default)
+}
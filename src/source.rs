@@ -0,0 +1,122 @@
+use crate::{PriceMod, Quantity};
+
+use serde::{Serialize, Deserialize, Deserializer};
+
+// How an item's price was derived, nesting for models built from others
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PriceSource {
+    pub kind: ModelKind,
+    pub description: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub market: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modifier: Option<PriceMod>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub components: Vec<Component>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub models: Vec<PriceSource>, // the models a wrapping model priced with
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelKind {
+    SingleMarketSingleItemMinSell,
+    SingleMarketSingleItemMaxBuy,
+    SingleMarketMultiItemMaxBuy,
+    SubSingleItemsMaxBuy,
+    SingleMarketSingleItemDepth,
+    SingleMarketSingleItemPercentile,
+    AdjustedPrice,
+    ManufacturingCost,
+    MultiMarket,
+    Reprocessing,
+    Tiered,
+    Guarded,
+    Smoothed,
+    Fallback,
+    Rejected,
+    Legacy, // stored before sources were structured
+}
+
+// One input of a model priced from several items
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Component {
+    pub item: String,
+    pub quantity: Quantity,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<PriceSource>,
+}
+
+impl PriceSource {
+    pub fn new(kind: ModelKind, description: &str) -> Self {
+        PriceSource {
+            kind: kind,
+            description: description.to_string(),
+            market: None,
+            modifier: None,
+            notes: Vec::new(),
+            components: Vec::new(),
+            models: Vec::new(),
+        }
+    }
+
+    pub fn rejected() -> Self {
+        PriceSource::new(ModelKind::Rejected, "Rejected")
+    }
+
+    pub fn with_market(mut self, market: &str) -> Self {
+        self.market = Some(market.to_string());
+        self
+    }
+
+    pub fn with_modifier(mut self, modifier: PriceMod) -> Self {
+        self.modifier = Some(modifier);
+        self
+    }
+
+    pub fn with_note(mut self, note: String) -> Self {
+        self.notes.push(note);
+        self
+    }
+
+    pub fn with_component(
+        mut self,
+        item: &str,
+        quantity: Quantity,
+        source: Option<PriceSource>,
+    ) -> Self {
+        self.components.push(Component {
+            item: item.to_string(),
+            quantity: quantity,
+            source: source,
+        });
+        self
+    }
+
+    pub fn with_model(mut self, source: PriceSource) -> Self {
+        self.models.push(source);
+        self
+    }
+}
+
+// Reads sources stored as plain strings as well as structured ones
+pub fn deserialize_stored<'de, D>(deserializer: D) -> Result<PriceSource, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Structured(PriceSource),
+        Legacy(String),
+    }
+    Ok(match Stored::deserialize(deserializer)? {
+        Stored::Structured(source) => source,
+        Stored::Legacy(description) => PriceSource::new(
+            ModelKind::Legacy,
+            &description,
+        ),
+    })
+}